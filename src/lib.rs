@@ -8,37 +8,570 @@
 #![warn(rust_2018_idioms, missing_docs)]
 
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fmt,
     future::Future,
     mem,
+    panic::{self, AssertUnwindSafe, Location},
     pin::Pin,
     sync::{
-        atomic::{AtomicBool, Ordering::Relaxed},
-        Arc, Mutex,
+        atomic::{
+            AtomicU64,
+            Ordering::{AcqRel, Acquire, Relaxed, Release},
+        },
+        Arc, PoisonError, Weak,
     },
     task::{self, Poll, Waker},
+    time::Instant,
 };
 
-struct Wakers(Mutex<Vec<Waker>>);
+use sync::{AtomicBool, AtomicUsize, Mutex, MutexGuard};
 
-impl Default for Wakers {
-    fn default() -> Self {
-        Self(Mutex::new(vec![]))
+/// Synchronization primitives, swapped for their loom counterparts when model checking.
+mod sync {
+    #[cfg(loom)]
+    pub(crate) use loom::sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Mutex, MutexGuard,
+    };
+
+    #[cfg(not(loom))]
+    pub(crate) use std::sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Mutex, MutexGuard,
+    };
+}
+
+/// Lock `mutex`, recovering from poisoning.
+///
+/// Every critical section in this crate leaves its data consistent (waking is idempotent, and
+/// causes are written at most once), so a panic while locked must not wedge completion for
+/// everyone else sharing the context.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Registered wakers, woken in FIFO registration order.
+///
+/// Each registration occupies a keyed slot, which a waiter can update in place or remove when
+/// it stops waiting.
+#[derive(Default)]
+struct Wakers(Mutex<Slots>);
+
+/// The number of task slots held before the slots of tasks that may have stopped polling are
+/// evicted. See [register_task][Wakers::register_task].
+const TASK_SLOTS: usize = 64;
+
+/// The identity of the task a waker wakes.
+type Task = (usize, usize);
+
+#[derive(Default)]
+struct Slots {
+    next: u64,
+    wakers: BTreeMap<u64, Waker>,
+    /// The slots of tasks polling [Context] futures, keyed by the identity of their wakers.
+    tasks: HashMap<Task, u64>,
+    /// Tasks evicted at the last eviction, and how many of them have registered again since.
+    evicted: HashSet<Task>,
+    survivors: usize,
+    /// The number of task slots that triggers the next eviction, if above [TASK_SLOTS].
+    limit: usize,
+}
+
+impl Slots {
+    fn insert(&mut self, waker: &Waker) -> u64 {
+        let key = self.next;
+        self.next += 1;
+        self.wakers.insert(key, waker.clone());
+        key
+    }
+
+    /// Releases all task slots, returning their wakers to be woken.
+    ///
+    /// The next eviction happens at twice the number of evicted tasks that registered again
+    /// since the last one, so tasks still waiting cost amortized O(1) spurious wakeups each.
+    fn evict(&mut self) -> Vec<Waker> {
+        let tasks = mem::take(&mut self.tasks);
+        self.limit = 2 * mem::take(&mut self.survivors);
+        let wakers = tasks
+            .values()
+            .filter_map(|k| self.wakers.remove(k))
+            .collect();
+        self.evicted = tasks.into_keys().collect();
+        wakers
     }
 }
 
 impl Wakers {
-    fn register(&self, waker: &Waker) {
-        let mut wakers = self.0.lock().unwrap();
-        wakers.push(waker.clone());
+    fn register(&self, waker: &Waker) -> u64 {
+        self.update(None, waker)
+    }
+
+    /// Stores `waker` in the slot `key` if it's still occupied, or in a new slot otherwise,
+    /// returning the key of the slot used.
+    fn update(&self, key: Option<u64>, waker: &Waker) -> u64 {
+        let mut slots = lock(&self.0);
+        if let Some(slot) = key.and_then(|k| slots.wakers.get_mut(&k)) {
+            if !slot.will_wake(waker) {
+                *slot = waker.clone();
+            }
+            return key.unwrap();
+        }
+        slots.insert(waker)
+    }
+
+    /// Registers `waker` in the slot of its task, so repeated polls from one task occupy a
+    /// single slot. Task slots are never released by [remove][Wakers::remove].
+    ///
+    /// As a task may stop polling without releasing its slot, all task slots are evicted once
+    /// there are too many of them. Evicted wakers are woken, so tasks still waiting register
+    /// again, while those of tasks that have gone away are dropped.
+    fn register_task(&self, waker: &Waker) {
+        let task = (waker.data() as usize, waker.vtable() as *const _ as usize);
+        let mut slots = lock(&self.0);
+        if let Some(key) = slots.tasks.get(&task) {
+            if slots.wakers.contains_key(key) {
+                return;
+            }
+        }
+        let evicted = if slots.tasks.len() >= slots.limit.max(TASK_SLOTS) {
+            slots.evict()
+        } else {
+            Vec::new()
+        };
+        if slots.evicted.remove(&task) {
+            slots.survivors += 1;
+        }
+        let key = slots.insert(waker);
+        slots.tasks.insert(task, key);
+        drop(slots);
+
+        evicted.into_iter().for_each(Waker::wake);
+    }
+
+    fn remove(&self, key: u64) {
+        lock(&self.0).wakers.remove(&key);
+    }
+
+    /// Wakes all registered wakers, returning how many were woken.
+    fn notify_all(&self) -> usize {
+        let wakers = {
+            let mut slots = lock(&self.0);
+            slots.tasks.clear();
+            slots.evicted.clear();
+            mem::take(&mut slots.wakers)
+        };
+        let woken = wakers.len();
+        wakers.into_values().for_each(|w| w.wake());
+        woken
+    }
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn next_id() -> u64 {
+    #[cfg(feature = "test-util")]
+    {
+        if let Some(id) = test_util::next_id() {
+            return id;
+        }
+    }
+
+    NEXT_ID.fetch_add(1, Relaxed)
+}
+
+/// Live guard accounting for [Context::drain_guards].
+#[derive(Default)]
+struct Drain {
+    live: usize,
+    epoch: u64,
+}
+
+/// Completion state, written once per generation by the completing call.
+#[derive(Default)]
+struct State {
+    cause: Option<Cause>,
+    /// The cause of the previous generation, if this context was [reset][Context::reset].
+    last: Option<Cause>,
+    source: Option<Source>,
+    callbacks: Vec<Box<dyn FnOnce() + Send>>,
+    children: Vec<Weak<Inner>>,
+    dependents: Vec<Weak<dyn Notify>>,
+    /// Dependents registered with other contexts on behalf of this one, which only hold them
+    /// weakly, so they're pruned once this context is dropped.
+    links: Vec<Arc<dyn Notify>>,
+    #[cfg(feature = "debug")]
+    location: Option<&'static Location<'static>>,
+}
+
+/// The work remaining after a context has stored its cause of completion.
+struct Settled {
+    callbacks: Vec<Box<dyn FnOnce() + Send>>,
+    children: Vec<Weak<Inner>>,
+    dependents: Vec<Weak<dyn Notify>>,
+}
+
+impl Settled {
+    fn run(self) {
+        self.callbacks.into_iter().for_each(|f| f());
+        self.dependents
+            .iter()
+            .filter_map(Weak::upgrade)
+            .for_each(|d| d.notify());
+    }
+}
+
+/// A comparator ranking causes, as used by [any_by][Context::any_by].
+type Ranking = dyn Fn(&Cause, &Cause) -> std::cmp::Ordering + Send + Sync;
+
+struct Inner {
+    id: u64,
+    deadline: Mutex<Option<Instant>>,
+    #[cfg(feature = "tokio")]
+    rearm: Arc<tokio::sync::Notify>,
+    cond: AtomicBool,
+    generation: AtomicUsize,
+    state: Mutex<State>,
+    wake: Wakers,
+    shared: AtomicUsize,
+    guards: Mutex<Drain>,
+    drained: Wakers,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self::new(next_id(), None)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // only weak observers can still be waiting at this point
+        self.wake.notify_all();
+    }
+}
+
+impl Inner {
+    fn new(id: u64, deadline: Option<Instant>) -> Self {
+        Self {
+            id,
+            deadline: Mutex::new(deadline),
+            #[cfg(feature = "tokio")]
+            rearm: Arc::new(tokio::sync::Notify::new()),
+            cond: AtomicBool::new(false),
+            generation: AtomicUsize::new(0),
+            state: Mutex::new(Default::default()),
+            wake: Wakers::default(),
+            shared: AtomicUsize::new(0),
+            guards: Mutex::new(Default::default()),
+            drained: Wakers::default(),
+        }
+    }
+
+    fn acquire_guard(&self) {
+        self.acquire_guards(1);
+    }
+
+    fn acquire_guards(&self, n: usize) {
+        lock(&self.guards).live += n;
+    }
+
+    fn release_guard(&self) {
+        let mut guards = lock(&self.guards);
+        guards.live -= 1;
+        if guards.live == 0 {
+            guards.epoch += 1;
+            drop(guards);
+            self.drained.notify_all();
+        }
+    }
+
+    /// Completes with `cause` if not already completed, returning the number of local waiters
+    /// woken if it did.
+    ///
+    /// Completion happens in phases. First the cause is stored in this context and then in
+    /// each of its live descendants, and only then is `cond` released for all of them, so any
+    /// observer of the completion of one will observe the completion of its descendants.
+    /// Finally waiters are woken, one context at a time starting with this one, with the
+    /// callbacks and dependents of each run after its waiters are woken.
+    ///
+    /// Every `cond` is released before any waker slots are locked for waking, which is what
+    /// [poll_ref][Context::poll_ref] relies on to never miss a wakeup.
+    #[track_caller]
+    fn complete_with(&self, cause: Cause, source: Source) -> Option<usize> {
+        self.complete_at(cause, source, Location::caller())
+    }
+
+    fn complete_at(
+        &self,
+        cause: Cause,
+        source: Source,
+        at: &'static Location<'static>,
+    ) -> Option<usize> {
+        let mut settled = self.settle(&cause, source, at)?;
+
+        // settle all live descendants breadth first before waking anyone
+        let mut queue = mem::take(&mut settled.children);
+        let mut descendants = Vec::new();
+        let mut next = 0;
+        while let Some(child) = queue.get(next).map(Weak::upgrade) {
+            next += 1;
+            let child = match child {
+                Some(child) => child,
+                None => continue,
+            };
+            if let Some(mut s) = child.settle(&cause, Source::Parent, at) {
+                queue.append(&mut s.children);
+                descendants.push((child, s));
+            }
+        }
+
+        // descendants are released deepest first, so observing any cond implies its subtree
+        for (child, _) in descendants.iter().rev() {
+            child.cond.store(true, Release);
+        }
+        self.cond.store(true, Release);
+
+        let woken = self.wake.notify_all();
+        settled.run();
+        for (child, settled) in descendants {
+            child.wake.notify_all();
+            settled.run();
+        }
+        Some(woken)
+    }
+
+    /// Stores the cause of completion if not already completed, returning what remains to be
+    /// done to finish it. Doesn't release `cond`.
+    fn settle(
+        &self,
+        cause: &Cause,
+        source: Source,
+        at: &'static Location<'static>,
+    ) -> Option<Settled> {
+        let mut state = lock(&self.state);
+        if state.cause.is_some() {
+            return None;
+        }
+        state.cause = Some(cause.clone());
+        state.source = Some(source);
+        #[cfg(feature = "debug")]
+        {
+            state.location = Some(at);
+        }
+        #[cfg(not(feature = "debug"))]
+        let _ = at;
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("context_completions_total", "cause" => cause.label()).increment(1);
+
+        Some(Settled {
+            callbacks: mem::take(&mut state.callbacks),
+            children: mem::take(&mut state.children),
+            dependents: mem::take(&mut state.dependents),
+        })
+    }
+
+    /// Starts a new generation if completed, returning whether it did.
+    ///
+    /// The generation is advanced before `cond` is cleared, so an observer that misses the
+    /// completion of a generation will see that it has passed instead.
+    fn reset(&self) -> bool {
+        let mut state = lock(&self.state);
+        if state.cause.is_none() {
+            return false;
+        }
+        state.last = state.cause.take();
+        state.source = None;
+        #[cfg(feature = "debug")]
+        {
+            state.location = None;
+        }
+        self.generation.fetch_add(1, Release);
+        self.cond.store(false, Release);
+        true
+    }
+
+    fn generation(&self) -> usize {
+        self.generation.load(Acquire)
+    }
+
+    fn drain_guards(self: Arc<Self>) -> GuardsDrained {
+        let start = {
+            let guards = lock(&self.guards);
+            Some(guards.epoch).filter(|_| guards.live > 0)
+        };
+
+        GuardsDrained {
+            inner: self,
+            start,
+            key: None,
+        }
+    }
+
+    fn on_complete(&self, f: Box<dyn FnOnce() + Send>) {
+        let mut state = lock(&self.state);
+        if state.cause.is_none() {
+            return state.callbacks.push(f);
+        }
+        drop(state);
+
+        f();
+    }
+
+    /// Registers `child` to be completed along with this context.
+    fn adopt(&self, child: &Arc<Inner>) {
+        let mut state = lock(&self.state);
+        let cause = match &state.cause {
+            Some(cause) => cause.clone(),
+            None => {
+                if state.children.len() == state.children.capacity() {
+                    state.children.retain(|c| c.strong_count() > 0);
+                }
+                return state.children.push(Arc::downgrade(child));
+            }
+        };
+        drop(state);
+
+        child.complete_with(cause, Source::Parent);
+    }
+
+    /// Registers `dependent` to be notified when this context completes.
+    fn depend(&self, dependent: Weak<dyn Notify>) {
+        let mut state = lock(&self.state);
+        if state.cause.is_none() {
+            if state.dependents.len() == state.dependents.capacity() {
+                state.dependents.retain(|d| d.strong_count() > 0);
+            }
+            return state.dependents.push(dependent);
+        }
+        drop(state);
+
+        if let Some(dependent) = dependent.upgrade() {
+            dependent.notify();
+        }
+    }
+
+    /// Returns true if `other` is this context or one that it completes, directly or through
+    /// its descendants.
+    fn reaches(self: &Arc<Self>, other: &Arc<Inner>) -> bool {
+        let mut seen = HashSet::new();
+        let mut queue = vec![self.clone()];
+        while let Some(inner) = queue.pop() {
+            if Arc::ptr_eq(&inner, other) {
+                return true;
+            }
+            if seen.insert(Arc::as_ptr(&inner) as usize) {
+                queue.extend(lock(&inner.state).children.iter().filter_map(Weak::upgrade));
+            }
+        }
+        false
+    }
+
+    /// Stops completing `child` along with this context.
+    fn disown(&self, child: &Arc<Inner>) {
+        lock(&self.state)
+            .children
+            .retain(|c| !std::ptr::eq(c.as_ptr(), Arc::as_ptr(child)));
+    }
+
+    fn is_completed(&self) -> bool {
+        self.cond.load(Acquire)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        *lock(&self.deadline)
+    }
+
+    fn cause(&self) -> Option<Cause> {
+        lock(&self.state).cause.clone()
+    }
+
+    #[cfg(feature = "debug")]
+    fn location(&self) -> Option<&'static Location<'static>> {
+        lock(&self.state).location
+    }
+
+    fn source(&self) -> Option<Source> {
+        lock(&self.state).source
+    }
+}
+
+/// The reason a [Context] completed.
+///
+/// More causes may be added in the future, so matches must include a wildcard arm.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Cause {
+    /// [complete][Context::complete] (or a variant thereof) was called.
+    Completed,
+    /// A derived [Guard] or the last derived [SharedGuard] was dropped.
+    Guard,
+    /// The [deadline][Context::deadline] passed.
+    Deadline,
+    /// [complete_with][Context::complete_with] was called with an error.
+    ///
+    /// Errors compare equal only if they are the same allocation.
+    Error(Arc<dyn Error + Send + Sync>),
+}
+
+impl Cause {
+    /// Returns an [Error][Cause::Error] cause wrapping `err`.
+    pub fn error(err: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Cause::Error(err.into().into())
+    }
+}
+
+impl PartialEq for Cause {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Cause::Error(a), Cause::Error(b)) => Arc::ptr_eq(a, b),
+            _ => mem::discriminant(self) == mem::discriminant(other),
+        }
     }
+}
 
-    fn notify_all(&self) {
-        mem::take(&mut *self.0.lock().unwrap())
-            .into_iter()
-            .for_each(|w| w.wake());
+impl Eq for Cause {}
+
+#[cfg(feature = "metrics")]
+impl Cause {
+    fn label(&self) -> &'static str {
+        match self {
+            Cause::Completed => "completed",
+            Cause::Guard => "guard",
+            Cause::Deadline => "deadline",
+            Cause::Error(_) => "error",
+        }
     }
 }
 
+/// Where the completion of a [Context] originated.
+///
+/// More sources may be added in the future, so matches must include a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Source {
+    /// The context itself was completed.
+    Local,
+    /// A parent of the context was completed.
+    Parent,
+}
+
+/// How completion of a parent affects a child derived with
+/// [child_with_policy][Context::child_with_policy].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildPolicy {
+    /// Completion and deadlines of the parent propagate to the child, as with
+    /// [child][Context::child].
+    Propagate,
+    /// The child is unaffected by the parent, as if it were a new root context.
+    Detached,
+    /// Completion of the parent propagates to the child, but the child doesn't keep the parent
+    /// alive. The deadline of the parent at the time of derivation is copied to the child.
+    Weak,
+}
+
 /// A future that can be completed externally as an asynchronous cancellation mechanism.
 ///
 /// Resolves if any of the following occur:
@@ -48,61 +581,1936 @@ impl Wakers {
 /// * a parent [Context] completes
 ///
 /// Clones can be expected to refer to the same logical entity.
+///
+/// Waiting on a context keeps it alive, so a context can only be dropped without completing
+/// once nothing is waiting on it but [weak observers][Context::completed_weak]. Those are
+/// woken when the last clone drops, and resolve with [WeakOutcome::Dropped].
 #[derive(Clone, Default)]
 pub struct Context {
-    parent: Option<Box<Context>>,
-    cond: Arc<AtomicBool>,
-    wake: Arc<Wakers>,
+    parent: Option<Arc<Parent>>,
+    inner: Arc<Inner>,
+}
+
+/// The link from a [Context] to its parent, shared by all clones of that context.
+///
+/// A [weak][ChildPolicy::Weak] link only refers to the local state of the parent, so that it
+/// can still be [orphaned][Context::orphan] without keeping the parent alive.
+struct Parent {
+    ctx: Option<Context>,
+    inner: Weak<Inner>,
+    severed: AtomicBool,
+}
+
+impl Parent {
+    fn new(ctx: &Context) -> Option<Arc<Self>> {
+        Some(Arc::new(Self {
+            ctx: Some(ctx.clone()),
+            inner: Arc::downgrade(&ctx.inner),
+            severed: AtomicBool::new(false),
+        }))
+    }
+
+    fn weak(ctx: &Context) -> Option<Arc<Self>> {
+        Some(Arc::new(Self {
+            ctx: None,
+            inner: Arc::downgrade(&ctx.inner),
+            severed: AtomicBool::new(false),
+        }))
+    }
 }
 
 impl Future for Context {
     type Output = ();
 
-    fn poll(mut self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
-        if let Some(Poll::Ready(())) = self.parent.as_mut().map(Pin::new).map(|p| p.poll(ctx)) {
-            return Poll::Ready(());
+    fn poll(self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        self.poll_ref(ctx)
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("id", &self.id())
+            .field("cause", &self.inner.cause())
+            .field("parent", &self.parent())
+            .finish()
+    }
+}
+
+impl Context {
+    /// Create a root context with the provided id.
+    ///
+    /// Ids are not checked for uniqueness; this is intended for tests that assert on ids.
+    pub fn with_id(id: u64) -> Self {
+        Self {
+            parent: None,
+            inner: Arc::new(Inner::new(id, None)),
         }
+    }
 
-        self.wake.register(ctx.waker());
+    /// Returns the id of this context, which is unique among automatically assigned ids in
+    /// this process.
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
 
-        if self.cond.load(Relaxed) {
-            return Poll::Ready(());
+    /// Returns an iterator over the ancestors of this context, starting with its parent and
+    /// ending at the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = Context> + '_ {
+        self.lineage().skip(1).cloned()
+    }
+
+    /// Returns a human readable description of this context and its ancestors, for debugging.
+    ///
+    /// Looks like `#3(pending, deadline=...) <- #2(completed) <- #1(root, pending)`.
+    pub fn hierarchy_string(&self) -> String {
+        let mut links = Vec::new();
+        let mut next = Some(self);
+
+        while let Some(ctx) = next {
+            next = ctx.parent();
+            let root = if next.is_none() { "root, " } else { "" };
+            let state = if ctx.is_completed() {
+                "completed"
+            } else {
+                "pending"
+            };
+            let link = match ctx.inner.deadline() {
+                Some(at) => format!("#{}({}{}, deadline={:?})", ctx.id(), root, state, at),
+                None => format!("#{}({}{})", ctx.id(), root, state),
+            };
+            links.push(link);
         }
 
-        Poll::Pending
+        links.join(" <- ")
     }
-}
 
-impl Context {
     /// Create a RAII guard that will [complete][Context::complete] this context (and any
     /// derived children) when the guard is dropped.
     pub fn guard(&self) -> Guard {
-        Guard(self.clone())
+        self.inner.acquire_guard();
+
+        Guard {
+            handle: self.handle(),
+            on_drop: None,
+            #[cfg(feature = "tokio")]
+            timer: None,
+        }
+    }
+
+    /// Create a RAII guard that will run `on_drop` with this context when dropped, instead of
+    /// completing it. `on_drop` may complete this context, another one, or none at all.
+    ///
+    /// Unlike other guards, the returned guard keeps this context and its parents alive. If
+    /// `on_drop` panics, the guard is still released before the panic resumes, and the panic
+    /// is discarded if the guard is being dropped during another panic.
+    pub fn guard_with(&self, on_drop: impl FnOnce(&Context) + Send + 'static) -> Guard {
+        let ctx = self.clone();
+        let mut guard = self.guard();
+        guard.on_drop = Some(Box::new(move || on_drop(&ctx)));
+        guard
+    }
+
+    /// Create a RAII guard that will complete `target` (rather than this context) when the
+    /// guard is dropped.
+    ///
+    /// This context is unaffected by the guard, which counts toward the guards of `target`
+    /// for the purposes of [drain_guards][Context::drain_guards].
+    pub fn guard_completing(&self, target: &Context) -> Guard {
+        target.guard()
+    }
+
+    /// Like [guard][Context::guard], but also completes this context with [Cause::Deadline]
+    /// if the guard is still live after `dur`.
+    ///
+    /// Must be called within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn guard_for(&self, dur: std::time::Duration) -> Guard {
+        let inner = self.inner.clone();
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(dur).await;
+            inner.complete_with(Cause::Deadline, Source::Local);
+        });
+
+        let mut guard = self.guard();
+        guard.timer = Some(timer);
+        guard
+    }
+
+    /// Create a reference counted RAII guard that will [complete][Context::complete] this
+    /// context (and any derived children) when the last shared guard is dropped.
+    pub fn shared_guard(&self) -> SharedGuard {
+        self.inner.acquire_guard();
+        self.inner.shared.fetch_add(1, Relaxed);
+        SharedGuard(self.clone())
+    }
+
+    /// Create `n` [shared guards][Context::shared_guard] at once, such that this context
+    /// completes when the last of them is dropped.
+    pub fn guards(&self, n: usize) -> Vec<SharedGuard> {
+        self.inner.acquire_guards(n);
+        self.inner.shared.fetch_add(n, Relaxed);
+        (0..n).map(|_| SharedGuard(self.clone())).collect()
+    }
+
+    /// Create a [Tracker] for a worker of this context, which counts toward the live guards
+    /// awaited by [drain_guards][Context::drain_guards] and [drained][Context::drained], but
+    /// doesn't complete this context when dropped.
+    pub fn track(&self) -> Tracker {
+        self.inner.acquire_guard();
+        Tracker(self.inner.clone())
+    }
+
+    /// Returns a future that resolves once this context has completed and no [trackers][Tracker]
+    /// or guards of it remain live, for awaiting the cleanup of workers after shutdown.
+    pub fn drained(&self) -> Drained {
+        Drained {
+            inner: self.inner.clone(),
+            generation: self.inner.generation(),
+            key: None,
+            drain: None,
+        }
+    }
+
+    /// Drive `fut` to completion while holding a [shared guard][Context::shared_guard].
+    ///
+    /// The guard is acquired immediately, and released when `fut` finishes or the returned
+    /// future is dropped, whichever comes first. Nested scopes on the same context only
+    /// complete it when the outermost scope exits.
+    pub fn guard_scope<Fut: Future>(&self, fut: Fut) -> GuardScope<Fut> {
+        GuardScope {
+            guard: Some(self.shared_guard()),
+            fut,
+        }
+    }
+
+    /// Returns a future that resolves once no [guards][Guard] or [shared guards][SharedGuard]
+    /// of this context are live, regardless of whether it has completed.
+    ///
+    /// If no guards are live when this is called, the future resolves immediately. Otherwise
+    /// it resolves the next time the live guard count reaches zero, even if new guards have
+    /// been issued since.
+    pub fn drain_guards(&self) -> GuardsDrained {
+        self.inner.clone().drain_guards()
     }
 
     /// Complete this context (and any derived children).
+    ///
+    /// The cause of completion is stored in this context and all of its children before any
+    /// waiter is woken, so a woken waiter of any of them always observes it. Waiters of each
+    /// context are woken in the order they were registered, so the longest waiting task is
+    /// woken first.
+    #[track_caller]
     pub fn complete(&self) {
-        self.cond.store(true, Relaxed);
-        self.wake.notify_all();
+        self.inner.complete_with(Cause::Completed, Source::Local);
     }
 
-    /// Derive a child context. Completion of the parent (self) will propagate to the child,
-    /// but not vice-versa.
-    pub fn child(&self) -> Self {
-        Self {
-            parent: Some(Box::new(self.clone())),
-            ..Self::default()
+    /// Complete this context (and any derived children) with `cause`, which children observe
+    /// with a [source][Context::source] of [Source::Parent].
+    ///
+    /// Has no effect if this context has already completed, so the first cause wins.
+    #[track_caller]
+    pub fn complete_with(&self, cause: Cause) {
+        self.inner.complete_with(cause, Source::Local);
+    }
+
+    /// Reset a completed context so that it can be completed again, returning false if it
+    /// hasn't completed locally yet.
+    ///
+    /// Each reset starts a new generation. [Completion] futures resolve with the generation
+    /// they were created in, so those created before the reset have already resolved, while
+    /// those created after it wait for the next completion. Children derived before the reset
+    /// remain completed.
+    ///
+    /// A context derived from a parent still observes that parent after a reset: it is
+    /// completed again right away if the parent is still completed, and otherwise by the next
+    /// completion of the parent.
+    pub fn reset(&self) -> bool {
+        if !self.inner.reset() {
+            return false;
+        }
+        if let Some(parent) = self.parent.as_deref() {
+            if let Some(inner) = parent.inner.upgrade() {
+                if !parent.severed.load(Acquire) {
+                    inner.disown(&self.inner);
+                    inner.adopt(&self.inner);
+                }
+            }
         }
+        true
     }
-}
 
-/// A RAII guard that will [complete][Context::complete] its source context when dropped.
-///
-/// Holding the guard does not prevent completion from other sources.
-pub struct Guard(Context);
+    /// Wake all tasks currently waiting on this context without completing it, forcing them
+    /// to poll again.
+    ///
+    /// This is always safe, as futures must tolerate spurious wakeups.
+    pub fn wake_all(&self) {
+        self.inner.wake.notify_all();
+    }
 
-impl Drop for Guard {
-    fn drop(&mut self) {
-        self.0.complete();
+    /// Complete this context (and any derived children) only if it hasn't already completed.
+    ///
+    /// Returns true if this call performed the completion.
+    #[track_caller]
+    pub fn try_complete(&self) -> bool {
+        self.complete_reporting().is_some()
+    }
+
+    /// Like [try_complete][Context::try_complete], but returns the number of tasks waiting on
+    /// this context that were woken if this call performed the completion.
+    #[track_caller]
+    pub fn complete_reporting(&self) -> Option<usize> {
+        self.inner.complete_with(Cause::Completed, Source::Local)
+    }
+
+    /// Like [try_complete][Context::try_complete], but also reports whether the completion
+    /// happened before the [deadline][Context::deadline].
+    ///
+    /// A context without a deadline is never late.
+    #[track_caller]
+    pub fn try_complete_with_timeout(&self) -> TryComplete {
+        let now = now();
+
+        if !self.try_complete() {
+            return TryComplete::AlreadyCompleted;
+        }
+
+        match self.deadline() {
+            Some(at) if at < now => TryComplete::WonAfterDeadline,
+            _ => TryComplete::WonBeforeDeadline,
+        }
+    }
+
+    /// Returns the deadline of this context, which is the earliest deadline of it and any of
+    /// its parents.
+    ///
+    /// Whether a deadline completes the context when it passes depends on how it was set.
+    /// Contexts created with [budget][Context::budget], [with_deadline][Context::with_deadline],
+    /// [with_timeout][Context::with_timeout] or [child_with_timeout][Context::child_with_timeout]
+    /// complete with [Cause::Deadline] once it passes, as do those given a deadline that has
+    /// already passed through [set_deadline][Context::set_deadline]. Deadlines set with
+    /// [child_with_deadline][Context::child_with_deadline] are informational only, and never
+    /// complete the context.
+    pub fn deadline(&self) -> Option<Instant> {
+        let parent = self.parent().and_then(|p| p.deadline());
+
+        earliest(self.inner.deadline(), parent)
+    }
+
+    /// Returns the earlier of the [deadlines][Context::deadline] of this context and `other`.
+    pub fn min_deadline_with(&self, other: &Context) -> Option<Instant> {
+        earliest(self.deadline(), other.deadline())
+    }
+
+    /// Returns true if this context completes within `dur`, or false if it doesn't.
+    #[cfg(feature = "tokio")]
+    pub async fn completed_or_timeout(&self, dur: std::time::Duration) -> bool {
+        tokio::time::timeout(dur, self.clone()).await.is_ok()
+    }
+
+    /// Returns a future that resolves when this context completes, without keeping it alive.
+    ///
+    /// If this context is dropped before completing, the future yields [WeakOutcome::Dropped].
+    /// Outstanding [handles][Handle] keep the context alive.
+    pub fn completed_weak(&self) -> WeakCompletion {
+        WeakCompletion {
+            inner: Arc::downgrade(&self.inner),
+            key: None,
+        }
+    }
+
+    /// Returns a cheaply cloneable future that resolves when this context completes.
+    #[cfg(feature = "futures")]
+    pub fn completed_shared(&self) -> futures::future::Shared<Context> {
+        futures::FutureExt::shared(self.clone())
+    }
+
+    /// Register a callback to be invoked exactly once when this context completes.
+    ///
+    /// Callbacks run on the completing thread after waiters are woken, in registration order,
+    /// and before the callbacks of any children. If this context has already completed, `f`
+    /// is invoked immediately.
+    pub fn on_complete(&self, f: impl FnOnce() + Send + 'static) {
+        self.inner.on_complete(Box::new(f));
+    }
+
+    /// Returns a [watch][tokio::sync::watch] receiver that holds `false` until this context
+    /// completes, and `true` thereafter.
+    ///
+    /// The update is sent from an [on_complete][Context::on_complete] callback, so the same
+    /// ordering guarantees apply.
+    #[cfg(feature = "tokio")]
+    pub fn watch(&self) -> tokio::sync::watch::Receiver<bool> {
+        let (tx, rx) = tokio::sync::watch::channel(false);
+        self.on_complete(move || {
+            tx.send_replace(true);
+        });
+
+        rx
+    }
+
+    /// Create a root context that completes when `token` is cancelled.
+    ///
+    /// The task watching `token` exits once either the context completes or is dropped.
+    /// Must be called within a tokio runtime, unless `token` has already been cancelled.
+    #[cfg(feature = "tokio-util")]
+    pub fn from_token(token: tokio_util::sync::CancellationToken) -> Self {
+        let ctx = Self::default();
+        if token.is_cancelled() {
+            ctx.complete();
+            return ctx;
+        }
+
+        let target = Arc::downgrade(&ctx.inner);
+        let mut completion = ctx.completed_weak();
+        let mut cancelled = Box::pin(token.cancelled_owned());
+        tokio::spawn(async move {
+            let cancelled = std::future::poll_fn(|cx| {
+                if Pin::new(&mut completion).poll(cx).is_ready() {
+                    return Poll::Ready(false);
+                }
+                cancelled.as_mut().poll(cx).map(|_| true)
+            })
+            .await;
+
+            if let Some(inner) = target.upgrade().filter(|_| cancelled) {
+                inner.complete_with(Cause::Completed, Source::Local);
+            }
+        });
+
+        ctx
+    }
+
+    /// Returns a [CancellationToken][tokio_util::sync::CancellationToken] that is cancelled
+    /// when this context completes.
+    ///
+    /// Cancelling the token has no effect on this context; use
+    /// [from_token][Context::from_token] for the other direction.
+    #[cfg(feature = "tokio-util")]
+    pub fn to_token(&self) -> tokio_util::sync::CancellationToken {
+        let token = tokio_util::sync::CancellationToken::new();
+        let cancel = token.clone();
+        self.on_complete(move || cancel.cancel());
+
+        token
+    }
+
+    /// Returns an [AbortRegistration][futures::future::AbortRegistration] that is aborted
+    /// when this context completes, for use with [Abortable][futures::future::Abortable].
+    #[cfg(feature = "futures")]
+    pub fn to_abort_registration(&self) -> futures::future::AbortRegistration {
+        let (handle, registration) = futures::future::AbortHandle::new_pair();
+        self.on_complete(move || handle.abort());
+
+        registration
+    }
+
+    /// Returns a pinned, boxed future that resolves when this context completes, suitable
+    /// for storing in the state of another future and polling manually.
+    pub fn completed_pinned(&self) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> {
+        Box::pin(self.clone())
+    }
+
+    /// Returns a boxed future that resolves when this context completes.
+    #[cfg(feature = "futures")]
+    pub fn completed_boxed(&self) -> futures::future::BoxFuture<'static, ()> {
+        Box::pin(self.clone())
+    }
+
+    /// Returns a stream yielding the index and [Cause] of each of `ctxs` as it completes,
+    /// which ends once all of them have completed.
+    #[cfg(feature = "stream")]
+    pub fn completed_stream_multi(
+        ctxs: Vec<Context>,
+    ) -> impl futures::Stream<Item = (usize, Cause)> + Send + 'static {
+        ctxs.into_iter()
+            .enumerate()
+            .map(|(i, ctx)| async move { (i, ctx.into_future_with_cause().await) })
+            .collect::<futures::stream::FuturesUnordered<_>>()
+    }
+
+    /// Run `fut` until it finishes or this context completes, whichever comes first.
+    ///
+    /// Yields the output of `fut`, or `fut` itself if this context completed first so that
+    /// it can be resumed elsewhere. A context that has already completed wins over a future
+    /// that is ready.
+    #[cfg(feature = "futures")]
+    pub async fn select_against<F>(&self, fut: F) -> futures::future::Either<F::Output, F>
+    where
+        F: Future + Unpin,
+    {
+        use futures::future::{select, Either};
+
+        match select(self.completed(), fut).await {
+            Either::Left(((), fut)) => Either::Right(fut),
+            Either::Right((out, _)) => Either::Left(out),
+        }
+    }
+
+    /// Returns `Ok(())` if this context completes within `dur`, or [Elapsed] if it doesn't.
+    #[cfg(feature = "tokio")]
+    pub async fn completed_for(&self, dur: std::time::Duration) -> Result<(), Elapsed> {
+        tokio::time::timeout(dur, self.clone())
+            .await
+            .map_err(|_| Elapsed(()))
+    }
+
+    /// Spawn `fut` onto the tokio runtime, dropping it if this context completes first.
+    ///
+    /// The task yields the output of `fut`, or the [Cause] of completion if this context
+    /// completed first. Tasks spawned on children of this context are torn down along with
+    /// those spawned on it when it completes.
+    ///
+    /// Must be called within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn spawn<F>(&self, fut: F) -> tokio::task::JoinHandle<Result<F::Output, Cause>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let ctx = self.clone();
+        tokio::spawn(async move { ctx.race_with_future(fut).await })
+    }
+
+    /// Collect the results of the tasks in `set` as they finish, until either all of them
+    /// have or this context completes.
+    ///
+    /// If this context completes first, any remaining tasks are aborted and left in `set`, and
+    /// only the results collected so far are returned.
+    #[cfg(feature = "tokio")]
+    pub async fn run_joinset<T: 'static>(
+        &self,
+        set: &mut tokio::task::JoinSet<T>,
+    ) -> Vec<Result<T, tokio::task::JoinError>> {
+        let mut completed = self.completed();
+        let mut results = Vec::with_capacity(set.len());
+
+        loop {
+            let next = std::future::poll_fn(|cx| {
+                if Pin::new(&mut completed).poll(cx).is_ready() {
+                    return Poll::Ready(None);
+                }
+                set.poll_join_next(cx).map(Some)
+            })
+            .await;
+
+            match next {
+                Some(Some(result)) => results.push(result),
+                Some(None) => return results,
+                None => {
+                    set.abort_all();
+                    return results;
+                }
+            }
+        }
+    }
+
+    /// Waits for this context to complete, yielding its [Cause], or [Cause::Deadline] if `dur`
+    /// elapses first.
+    ///
+    /// The context itself is not completed when `dur` elapses.
+    #[cfg(feature = "tokio")]
+    pub async fn completed_within(&self, dur: std::time::Duration) -> Cause {
+        self.completed_or_deadline(now() + dur).await
+    }
+
+    /// Waits for this context to complete, yielding its [Cause], or [Cause::Deadline] if `at`
+    /// passes first.
+    ///
+    /// The context itself is not completed when `at` passes.
+    #[cfg(feature = "tokio")]
+    pub async fn completed_or_deadline(&self, at: Instant) -> Cause {
+        match tokio::time::timeout_at(at.into(), self.completed()).await {
+            Ok(()) => self.completed_cause(),
+            Err(_) => Cause::Deadline,
+        }
+    }
+
+    /// Returns the [Cause] of completion, or `None` if this context hasn't completed.
+    ///
+    /// A context completed by a parent reports the cause of that parent.
+    pub fn cause(&self) -> Option<Cause> {
+        self.inner.cause()
+    }
+
+    /// Returns the location of the call that completed this context, or `None` if it hasn't
+    /// completed.
+    ///
+    /// A context completed by a parent reports the location that completed that parent.
+    #[cfg(feature = "debug")]
+    pub fn completed_at(&self) -> Option<&'static Location<'static>> {
+        self.inner.location()
+    }
+
+    /// Returns true if this context has completed, whether locally or through a parent.
+    ///
+    /// Completion of a parent is propagated eagerly, so this only checks the local state.
+    pub fn is_completed(&self) -> bool {
+        self.inner.is_completed()
+    }
+
+    /// Block the current thread until this context completes, for use outside of async code.
+    ///
+    /// This must not be called from within an async runtime, as it would block the executor.
+    pub fn wait(&self) {
+        struct Unpark(std::thread::Thread);
+
+        impl std::task::Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut ctx = task::Context::from_waker(&waker);
+        let mut completion = self.completed();
+        while Pin::new(&mut completion).poll(&mut ctx).is_pending() {
+            std::thread::park();
+        }
+    }
+
+    /// Returns true if this context was completed locally rather than through a parent, i.e.
+    /// if its [source][Context::source] is [Source::Local].
+    pub fn is_completed_local(&self) -> bool {
+        self.inner.is_completed() && self.inner.source() == Some(Source::Local)
+    }
+
+    /// Panics if this context completes within a short grace period.
+    #[cfg(all(feature = "test-util", feature = "tokio"))]
+    pub async fn assert_pending(&self) {
+        if self.completed_for(test_util::JIFFY).await.is_ok() {
+            panic!("expected {:?} to be pending", self);
+        }
+    }
+
+    /// Panics unless this context completes within a short grace period.
+    #[cfg(all(feature = "test-util", feature = "tokio"))]
+    pub async fn assert_completed(&self) {
+        if self.completed_for(test_util::JIFFY).await.is_err() {
+            panic!("expected {:?} to complete", self);
+        }
+    }
+
+    /// Returns true if any ancestor of this context has completed, ignoring the local state of
+    /// this context. Intended for testing propagation in isolation.
+    #[cfg(feature = "test-util")]
+    pub fn poll_parent_only(&self) -> bool {
+        self.lineage().skip(1).any(|ctx| ctx.inner.is_completed())
+    }
+
+    /// Returns the [Source] of completion, or `None` if this context hasn't completed.
+    pub fn source(&self) -> Option<Source> {
+        self.inner.source()
+    }
+
+    /// Returns a future that resolves when this context completes, without consuming it.
+    pub fn completed(&self) -> Completion<'_> {
+        Completion {
+            ctx: self,
+            key: None,
+            generation: self.inner.generation(),
+        }
+    }
+
+    /// Returns an owned future that resolves when this context completes.
+    ///
+    /// The future is guaranteed to be ready on its first poll if this context has already
+    /// completed by then, so it can be checked without awaiting (e.g. with `now_or_never`).
+    pub fn now_or_pending(&self) -> impl Future<Output = ()> + 'static {
+        self.clone()
+    }
+
+    /// Returns a future that resolves to `value` when this context completes.
+    pub async fn completed_map<T>(&self, value: T) -> T {
+        self.completed().await;
+        value
+    }
+
+    /// Convert this context into a future that yields the [Cause] of completion.
+    pub async fn into_future_with_cause(mut self) -> Cause {
+        (&mut self).await;
+        self.completed_cause()
+    }
+
+    /// Run `fut` until it finishes or this context completes, whichever comes first.
+    ///
+    /// Returns the output of `fut`, or the [Cause] of completion if this context completed
+    /// first. A context that has already completed wins over a future that is ready.
+    pub fn race_with_future<F: Future>(&self, fut: F) -> Race<'_, F> {
+        Race {
+            completion: self.completed(),
+            fut,
+        }
+    }
+
+    /// Run `fut` until it finishes or this context completes, whichever comes first.
+    ///
+    /// Returns the output of `fut`, or `None` if this context completed first.
+    pub fn run_until<F: Future>(&self, fut: F) -> RunUntil<'_, F> {
+        RunUntil {
+            race: self.race_with_future(fut),
+        }
+    }
+
+    /// Yield items from `stream` until it ends or this context completes, whichever comes
+    /// first.
+    ///
+    /// Completion is checked before each item, so no items are yielded once this context
+    /// has completed.
+    #[cfg(feature = "stream")]
+    pub fn stream_until<S: futures::Stream>(&self, stream: S) -> StreamUntil<'_, S> {
+        StreamUntil {
+            completion: self.completed(),
+            stream,
+        }
+    }
+
+    /// The cause of a completion that has been observed, which may belong to a generation
+    /// since [reset][Context::reset].
+    fn completed_cause(&self) -> Cause {
+        self.cause()
+            .or_else(|| lock(&self.inner.state).last.clone())
+            .expect("completed context has a cause")
+    }
+
+    /// Returns a [Handle] to the local completion state of this context.
+    pub fn handle(&self) -> Handle {
+        Handle(self.inner.clone())
+    }
+
+    /// Returns a [CompleterHandle] that can complete this context from a blocking thread.
+    pub fn completer(&self) -> CompleterHandle {
+        CompleterHandle(self.inner.clone())
+    }
+
+    /// Derive a child context. Completion of the parent (self) will propagate to the child,
+    /// but not vice-versa.
+    pub fn child(&self) -> Self {
+        self.child_with(None)
+    }
+
+    /// Derive a child context whose relationship to the parent (self) is governed by `policy`.
+    pub fn child_with_policy(&self, policy: ChildPolicy) -> Self {
+        match policy {
+            ChildPolicy::Propagate => self.child(),
+            ChildPolicy::Detached => Self::default(),
+            ChildPolicy::Weak => {
+                let inner = Arc::new(Inner::new(next_id(), self.deadline()));
+                self.inner.adopt(&inner);
+
+                Self {
+                    parent: Parent::weak(self),
+                    inner,
+                }
+            }
+        }
+    }
+
+    /// Create a root context along with a [Group] that derives and tracks children of it.
+    pub fn new_group() -> (Self, Group) {
+        let root = Self::default();
+        let group = Group {
+            root: root.clone(),
+            children: Mutex::new(vec![]),
+        };
+
+        (root, group)
+    }
+
+    /// Detach this context from its parent, returning a context that shares its local state
+    /// but has no parent.
+    ///
+    /// Detaching applies to the local state, so neither the returned context nor any clone of
+    /// this one (including `self`) will observe completion or deadlines of the former parent
+    /// going forward. Parents attached with [try_with_parent][Context::try_with_parent] and
+    /// children of this context remain attached.
+    pub fn orphan(&self) -> Context {
+        if let Some(parent) = self.parent.as_deref() {
+            parent.severed.store(true, Release);
+            if let Some(inner) = parent.inner.upgrade() {
+                inner.disown(&self.inner);
+            }
+        }
+
+        Context {
+            parent: None,
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Register `dependent` to be notified when this context completes, after any derived
+    /// children have been completed. If this context has already completed, `dependent` is
+    /// notified immediately.
+    ///
+    /// Only a weak reference is held, so registration doesn't keep `dependent` alive.
+    pub fn register_dependent(&self, dependent: Weak<dyn Notify>) {
+        self.inner.depend(dependent);
+    }
+
+    /// Create a context that completes when any of `ctxs` completes, with the [Cause] of the
+    /// first of them to do so.
+    ///
+    /// None of `ctxs` become parents of the returned context, nor are they kept alive by it,
+    /// and `ctxs` only refer to it weakly, so dropping it releases its registrations with
+    /// them. The returned context completes with a [source][Context::source] of
+    /// [Source::Local], the same as if it was completed directly, and never completes if
+    /// `ctxs` is empty.
+    pub fn any<I>(ctxs: I) -> Context
+    where
+        I: IntoIterator<Item = Context>,
+    {
+        Self::any_with(ctxs, None)
+    }
+
+    /// Like [any][Context::any], but reports the highest ranked [Cause] according to `cmp`
+    /// among all of `ctxs` that have completed when the first completion is observed.
+    ///
+    /// Ties go to the context that completed first, so a `cmp` that ranks all causes equally
+    /// behaves like [any][Context::any].
+    pub fn any_by<I, F>(ctxs: I, cmp: F) -> Context
+    where
+        I: IntoIterator<Item = Context>,
+        F: Fn(&Cause, &Cause) -> std::cmp::Ordering + Send + Sync + 'static,
+    {
+        Self::any_with(ctxs, Some(Arc::new(cmp)))
+    }
+
+    /// Create a context that completes once all of `ctxs` have completed, with the [Cause] of
+    /// the last of them to do so.
+    ///
+    /// None of `ctxs` become parents of the returned context, nor are they kept alive by it,
+    /// and `ctxs` only refer to it weakly, as with [any][Context::any]. The returned context is
+    /// completed immediately if `ctxs` is empty, and never completes if any of `ctxs` is dropped
+    /// without completing.
+    pub fn all<I>(ctxs: I) -> Context
+    where
+        I: IntoIterator<Item = Context>,
+    {
+        let merged = Context::default();
+        let ctxs: Vec<_> = ctxs.into_iter().collect();
+        if ctxs.is_empty() {
+            merged.complete();
+            return merged;
+        }
+        let remaining = Arc::new(AtomicUsize::new(ctxs.len()));
+
+        let links = ctxs.iter().map(|ctx| {
+            Arc::new(AllLink {
+                target: Arc::downgrade(&merged.inner),
+                source: Arc::downgrade(&ctx.inner),
+                remaining: remaining.clone(),
+            }) as Arc<dyn Notify>
+        });
+        merged.link(&ctxs, links.collect());
+
+        merged
+    }
+
+    /// Create a context that completes when either this context or `other` completes, with
+    /// the [Cause] of whichever did so first. See [any][Context::any].
+    pub fn merge(&self, other: &Context) -> Context {
+        Self::any(vec![self.clone(), other.clone()])
+    }
+
+    fn any_with<I>(ctxs: I, cmp: Option<Arc<Ranking>>) -> Context
+    where
+        I: IntoIterator<Item = Context>,
+    {
+        let merged = Context::default();
+        let ctxs: Vec<_> = ctxs.into_iter().collect();
+        let sources: Arc<[_]> = ctxs.iter().map(|c| Arc::downgrade(&c.inner)).collect();
+
+        let links = (0..ctxs.len()).map(|index| {
+            Arc::new(AnyLink {
+                target: Arc::downgrade(&merged.inner),
+                sources: sources.clone(),
+                index,
+                cmp: cmp.clone(),
+            }) as Arc<dyn Notify>
+        });
+        merged.link(&ctxs, links.collect());
+
+        merged
+    }
+
+    /// Registers `links[i]` as a dependent of `ctxs[i]`, keeping the links alive for as long
+    /// as this context is, and no longer.
+    fn link(&self, ctxs: &[Context], links: Vec<Arc<dyn Notify>>) {
+        lock(&self.inner.state).links = links.clone();
+        for (ctx, link) in ctxs.iter().zip(&links) {
+            ctx.inner.depend(Arc::downgrade(link));
+        }
+    }
+
+    /// Attach the local state of this context under `parent`, returning a context that shares
+    /// it and has `parent` as its parent.
+    ///
+    /// Completion of `parent` then propagates to this context and all of its clones, in
+    /// addition to that of any existing parent. Fails with [CycleError] if `parent` is this
+    /// context or would be completed by it, including through parents attached to other
+    /// clones of it.
+    pub fn try_with_parent(&self, parent: &Context) -> Result<Context, CycleError> {
+        if self.inner.reaches(&parent.inner) {
+            return Err(CycleError(()));
+        }
+
+        parent.inner.adopt(&self.inner);
+
+        Ok(Context {
+            parent: Parent::new(parent),
+            inner: self.inner.clone(),
+        })
+    }
+
+    /// Derive a child context with an informational [deadline][Context::deadline] of `at`,
+    /// which doesn't complete it when it passes.
+    pub fn child_with_deadline(&self, at: Instant) -> Self {
+        self.child_with(Some(at))
+    }
+
+    fn child_with(&self, deadline: Option<Instant>) -> Self {
+        let inner = Arc::new(Inner::new(next_id(), deadline));
+        self.inner.adopt(&inner);
+
+        Self {
+            parent: Parent::new(self),
+            inner,
+        }
+    }
+
+    /// Replace the local deadline of this context, re-arming any timer enforcing it.
+    ///
+    /// If `at` has already passed, this context completes with [Cause::Deadline] immediately.
+    #[track_caller]
+    pub fn set_deadline(&self, at: Instant) {
+        *lock(&self.inner.deadline) = Some(at);
+        if at <= now() {
+            self.inner.complete_with(Cause::Deadline, Source::Local);
+        }
+        #[cfg(feature = "tokio")]
+        self.inner.rearm.notify_one();
+    }
+
+    /// Remove the local deadline of this context, disarming any timer enforcing it.
+    pub fn clear_deadline(&self) {
+        *lock(&self.inner.deadline) = None;
+        #[cfg(feature = "tokio")]
+        self.inner.rearm.notify_one();
+    }
+
+    /// Derive a child context that completes with [Cause::Deadline] unless its deadline is
+    /// renewed within `dur` through the returned [BudgetHandle].
+    ///
+    /// Must be called within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn budget(&self, dur: std::time::Duration) -> (Self, BudgetHandle) {
+        let ctx = self.child_with(Some(now() + dur)).enforced();
+
+        (ctx.clone(), BudgetHandle(ctx))
+    }
+
+    /// Create a root context that completes with [Cause::Deadline] once `at` passes.
+    ///
+    /// The timer enforcing the deadline exits as soon as the context completes for any other
+    /// reason. Must be called within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn with_deadline(at: Instant) -> Self {
+        let ctx = Self {
+            parent: None,
+            inner: Arc::new(Inner::new(next_id(), Some(at))),
+        };
+
+        ctx.enforced()
+    }
+
+    /// Create a root context that completes with [Cause::Deadline] once `dur` elapses.
+    ///
+    /// Must be called within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn with_timeout(dur: std::time::Duration) -> Self {
+        Self::with_deadline(now() + dur)
+    }
+
+    /// Derive a child context that completes with [Cause::Deadline] once `dur` elapses, unless
+    /// the parent (self) completes first.
+    ///
+    /// Must be called within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn child_with_timeout(&self, dur: std::time::Duration) -> Self {
+        self.child_with(Some(now() + dur)).enforced()
+    }
+
+    #[cfg(feature = "tokio")]
+    fn enforced(self) -> Self {
+        match self.inner.deadline() {
+            Some(at) if at <= now() => {
+                self.inner.complete_with(Cause::Deadline, Source::Local);
+            }
+            _ => {
+                tokio::spawn(enforce_deadline(Arc::downgrade(&self.inner)));
+            }
+        }
+        self
+    }
+
+    /// Polls for completion, registering the waker only if this context is still pending.
+    ///
+    /// Parents complete their children eagerly, so only the local state is checked and the
+    /// waker is only registered locally, regardless of the depth of this context. A task that
+    /// polls repeatedly occupies a single slot, looked up by the identity of its waker, so
+    /// storage is bounded by the number of tasks waiting rather than the number of polls. Slots
+    /// of tasks that stopped polling are evicted once enough of them pile up, so tasks that
+    /// poll once and go away (say, by selecting against another future) don't leak wakers. That
+    /// slot is separate from those of [Completion] futures, so dropping one of those in the same
+    /// task doesn't unregister it.
+    ///
+    /// The waker is stored under the lock of the waker slots, and `cond` is then checked again
+    /// with `Acquire`. Completion stores `cond` with `Release` before taking that lock to wake,
+    /// so a concurrent completion either takes the lock after the waker was stored and wakes
+    /// it, or released `cond` before the second check, which then observes it.
+    fn poll_ref(&self, ctx: &mut task::Context<'_>) -> Poll<()> {
+        if self.is_completed() {
+            return Poll::Ready(());
+        }
+
+        self.inner.wake.register_task(ctx.waker());
+
+        if self.is_completed() {
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+
+    /// Iterates over this context followed by its ancestors.
+    fn lineage(&self) -> impl Iterator<Item = &Context> {
+        std::iter::successors(Some(self), |ctx| ctx.parent())
+    }
+
+    fn parent(&self) -> Option<&Context> {
+        self.parent
+            .as_deref()
+            .filter(|p| !p.severed.load(Acquire))
+            .and_then(|p| p.ctx.as_ref())
+    }
+}
+
+/// The error returned by [try_with_parent][Context::try_with_parent] if the new parent is a
+/// descendant of the context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleError(());
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("context cannot be parented under itself or a descendant")
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+/// An extension trait for running futures until a [Context] completes.
+pub trait CtxFutureExt: Future + Sized {
+    /// Run this future until it finishes or `ctx` completes, yielding `None` in the latter
+    /// case. See [run_until][Context::run_until].
+    fn until(self, ctx: &Context) -> RunUntil<'_, Self> {
+        ctx.run_until(self)
+    }
+
+    /// Run this future until it finishes or `ctx` completes, yielding the [Cause] of
+    /// completion in the latter case. See [race_with_future][Context::race_with_future].
+    fn until_err(self, ctx: &Context) -> Race<'_, Self> {
+        ctx.race_with_future(self)
+    }
+}
+
+impl<F: Future> CtxFutureExt for F {}
+
+/// An extension trait for ending streams when a [Context] completes.
+#[cfg(feature = "stream")]
+pub trait CtxStreamExt: futures::Stream + Sized {
+    /// Yield items from this stream until it ends or `ctx` completes. See
+    /// [stream_until][Context::stream_until].
+    fn until_completed(self, ctx: &Context) -> StreamUntil<'_, Self> {
+        ctx.stream_until(self)
+    }
+}
+
+#[cfg(feature = "stream")]
+impl<S: futures::Stream> CtxStreamExt for S {}
+
+/// Commonly used traits.
+pub mod prelude {
+    pub use crate::CtxFutureExt;
+    #[cfg(feature = "stream")]
+    pub use crate::CtxStreamExt;
+}
+
+/// A dependent of a [Context], notified when it completes.
+///
+/// See [register_dependent][Context::register_dependent].
+pub trait Notify: Send + Sync {
+    /// Called once on the completing thread when the context this was registered with
+    /// completes.
+    fn notify(&self);
+}
+
+/// Completes its target with the cause of a source of [any][Context::any].
+struct AnyLink {
+    target: Weak<Inner>,
+    sources: Arc<[Weak<Inner>]>,
+    index: usize,
+    cmp: Option<Arc<Ranking>>,
+}
+
+impl Notify for AnyLink {
+    fn notify(&self) {
+        let (target, inner) = match (self.target.upgrade(), self.sources[self.index].upgrade()) {
+            (Some(target), Some(inner)) => (target, inner),
+            _ => return,
+        };
+        let mut cause = inner.cause().expect("completed context has a cause");
+        if let Some(cmp) = &self.cmp {
+            for other in self.sources.iter().filter_map(|s| s.upgrade()?.cause()) {
+                if cmp(&other, &cause) == std::cmp::Ordering::Greater {
+                    cause = other;
+                }
+            }
+        }
+        target.complete_with(cause, Source::Local);
+    }
+}
+
+/// Completes its target with the cause of the last source of [all][Context::all].
+struct AllLink {
+    target: Weak<Inner>,
+    source: Weak<Inner>,
+    remaining: Arc<AtomicUsize>,
+}
+
+impl Notify for AllLink {
+    fn notify(&self) {
+        if self.remaining.fetch_sub(1, AcqRel) != 1 {
+            return;
+        }
+        let (target, inner) = match (self.target.upgrade(), self.source.upgrade()) {
+            (Some(target), Some(inner)) => (target, inner),
+            _ => return,
+        };
+        let cause = inner.cause().expect("completed context has a cause");
+        target.complete_with(cause, Source::Local);
+    }
+}
+
+/// A source of cancellation, implemented by [Context].
+///
+/// This trait is object safe, so libraries can accept any cancellation signal as a
+/// `&dyn Cancellation` and await it with [cancelled].
+pub trait Cancellation {
+    /// Poll for cancellation, registering the current task to be woken if not yet cancelled.
+    fn poll_cancelled(&self, ctx: &mut task::Context<'_>) -> Poll<()>;
+
+    /// Returns true if cancelled.
+    fn is_cancelled(&self) -> bool;
+}
+
+impl Cancellation for Context {
+    fn poll_cancelled(&self, ctx: &mut task::Context<'_>) -> Poll<()> {
+        self.poll_ref(ctx)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.is_completed()
+    }
+}
+
+impl<C: Cancellation + ?Sized> Cancellation for &C {
+    fn poll_cancelled(&self, ctx: &mut task::Context<'_>) -> Poll<()> {
+        (**self).poll_cancelled(ctx)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        (**self).is_cancelled()
+    }
+}
+
+/// Returns a future that resolves when `c` is cancelled.
+pub fn cancelled<C: Cancellation + ?Sized>(c: &C) -> Cancelled<'_, C> {
+    Cancelled(c)
+}
+
+/// A future that resolves when a [Cancellation] is cancelled, created by [cancelled].
+pub struct Cancelled<'a, C: ?Sized>(&'a C);
+
+impl<C: Cancellation + ?Sized> Future for Cancelled<'_, C> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        self.0.poll_cancelled(ctx)
+    }
+}
+
+/// A future that resolves when a [Context] completes, created by
+/// [completed][Context::completed].
+///
+/// The future belongs to the generation of the context it was created in, so it resolves once
+/// that generation completes, regardless of any later [reset][Context::reset].
+///
+/// Repeated polls reuse a single waker slot, which is released when the future is dropped,
+/// so recreating the future in a loop (e.g. in `select!`) doesn't accumulate wakers. The stored
+/// waker is only replaced if it wouldn't wake the polling task, so polling a pending future
+/// repeatedly (e.g. as the first branch of a `biased` select) doesn't clone wakers either.
+#[derive(Debug)]
+pub struct Completion<'a> {
+    ctx: &'a Context,
+    key: Option<u64>,
+    generation: usize,
+}
+
+impl Completion<'_> {
+    fn is_completed(&self) -> bool {
+        self.ctx.is_completed() || self.ctx.inner.generation() != self.generation
+    }
+}
+
+impl Future for Completion<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        if self.is_completed() {
+            return Poll::Ready(());
+        }
+        // children are completed eagerly by their parents, so the local slot suffices
+        self.key = Some(self.ctx.inner.wake.update(self.key, ctx.waker()));
+        if self.is_completed() {
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Completion<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.ctx.inner.wake.remove(key);
+        }
+    }
+}
+
+/// A completion is cancelled once its generation has completed, even if the context has been
+/// [reset][Context::reset] since. As polling by reference can't hold a slot of its own, the
+/// polling task is registered in its slot of the context, as when polling the context itself.
+impl Cancellation for Completion<'_> {
+    fn poll_cancelled(&self, ctx: &mut task::Context<'_>) -> Poll<()> {
+        if self.is_completed() {
+            return Poll::Ready(());
+        }
+        self.ctx.inner.wake.register_task(ctx.waker());
+        if self.is_completed() {
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.is_completed()
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// A future that runs another until it finishes or a [Context] completes, created by
+    /// [race_with_future][Context::race_with_future].
+    pub struct Race<'a, F> {
+        completion: Completion<'a>,
+        #[pin]
+        fut: F,
+    }
+}
+
+impl<F: Future> Future for Race<'_, F> {
+    type Output = Result<F::Output, Cause>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if Pin::new(&mut *this.completion).poll(ctx).is_ready() {
+            return Poll::Ready(Err(this.completion.ctx.completed_cause()));
+        }
+        this.fut.poll(ctx).map(Ok)
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// A future that runs another until it finishes or a [Context] completes, created by
+    /// [run_until][Context::run_until].
+    pub struct RunUntil<'a, F> {
+        #[pin]
+        race: Race<'a, F>,
+    }
+}
+
+impl<F: Future> Future for RunUntil<'_, F> {
+    type Output = Option<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        self.project().race.poll(ctx).map(Result::ok)
+    }
+}
+
+#[cfg(feature = "stream")]
+pin_project_lite::pin_project! {
+    /// A stream that yields items from another until it ends or a [Context] completes,
+    /// created by [stream_until][Context::stream_until].
+    pub struct StreamUntil<'a, S> {
+        completion: Completion<'a>,
+        #[pin]
+        stream: S,
+    }
+}
+
+#[cfg(feature = "stream")]
+impl<S: futures::Stream> futures::Stream for StreamUntil<'_, S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.project();
+        if Pin::new(&mut *this.completion).poll(ctx).is_ready() {
+            return Poll::Ready(None);
+        }
+        this.stream.poll_next(ctx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.completion.is_completed() {
+            return (0, Some(0));
+        }
+        (0, self.stream.size_hint().1)
+    }
+}
+
+/// A future that resolves when a [Context] completes or is dropped, created by
+/// [completed_weak][Context::completed_weak].
+#[derive(Debug)]
+pub struct WeakCompletion {
+    inner: Weak<Inner>,
+    key: Option<u64>,
+}
+
+impl Future for WeakCompletion {
+    type Output = WeakOutcome;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let inner = match self.inner.upgrade() {
+            Some(inner) => inner,
+            None => return Poll::Ready(WeakOutcome::Dropped),
+        };
+        if inner.is_completed() {
+            return Poll::Ready(WeakOutcome::Completed);
+        }
+        self.key = Some(inner.wake.update(self.key, ctx.waker()));
+        if inner.is_completed() {
+            return Poll::Ready(WeakOutcome::Completed);
+        }
+        // if this was the last reference, dropping it wakes us to observe the drop
+        Poll::Pending
+    }
+}
+
+/// A future that resolves once no guards of a [Context] are live, created by
+/// [drain_guards][Context::drain_guards].
+///
+/// The future only waits on the live guard count observed when it was created, so it resolves
+/// the next time that count reaches zero, even if new guards have been issued since.
+pub struct GuardsDrained {
+    inner: Arc<Inner>,
+    start: Option<u64>,
+    key: Option<u64>,
+}
+
+impl Future for GuardsDrained {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let start = match self.start {
+            Some(start) => start,
+            None => return Poll::Ready(()),
+        };
+
+        let guards = lock(&self.inner.guards);
+        if guards.epoch != start {
+            return Poll::Ready(());
+        }
+        let key = self.inner.drained.update(self.key, ctx.waker());
+        drop(guards);
+        self.key = Some(key);
+        Poll::Pending
+    }
+}
+
+impl Drop for GuardsDrained {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.inner.drained.remove(key);
+        }
+    }
+}
+
+/// A future that resolves once a [Context] has completed and no guards or trackers of it
+/// remain live, created by [drained][Context::drained].
+///
+/// Like [Completion], the future belongs to the generation of the context it was created in,
+/// and only starts waiting on guards once that generation has completed. It holds a strong
+/// reference to the context, so it can be moved into a spawned task.
+pub struct Drained {
+    inner: Arc<Inner>,
+    generation: usize,
+    key: Option<u64>,
+    drain: Option<GuardsDrained>,
+}
+
+impl Drained {
+    fn is_completed(&self) -> bool {
+        self.inner.is_completed() || self.inner.generation() != self.generation
+    }
+}
+
+impl Future for Drained {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        if self.drain.is_none() {
+            if !self.is_completed() {
+                self.key = Some(self.inner.wake.update(self.key, ctx.waker()));
+                if !self.is_completed() {
+                    return Poll::Pending;
+                }
+            }
+            if let Some(key) = self.key.take() {
+                self.inner.wake.remove(key);
+            }
+            self.drain = Some(self.inner.clone().drain_guards());
+        }
+        Pin::new(self.drain.as_mut().unwrap()).poll(ctx)
+    }
+}
+
+impl Drop for Drained {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.inner.wake.remove(key);
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// A future that runs another while holding a [SharedGuard] of a [Context], created by
+    /// [guard_scope][Context::guard_scope].
+    ///
+    /// The guard is released as soon as the inner future finishes, or when this is dropped.
+    pub struct GuardScope<F> {
+        guard: Option<SharedGuard>,
+        #[pin]
+        fut: F,
+    }
+}
+
+impl<F: Future> Future for GuardScope<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let out = match this.fut.poll(ctx) {
+            Poll::Ready(out) => out,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.guard.take();
+        Poll::Ready(out)
+    }
+}
+
+impl Drop for WeakCompletion {
+    fn drop(&mut self) {
+        if let (Some(key), Some(inner)) = (self.key, self.inner.upgrade()) {
+            inner.wake.remove(key);
+        }
+    }
+}
+
+/// A handle that can only [complete][Context::complete] its originating context, for handing
+/// to synchronous code such as a blocking thread.
+///
+/// Completion is thread safe and never blocks on waiters, so the async side observes it
+/// without any further coordination.
+#[derive(Clone)]
+pub struct CompleterHandle(Arc<Inner>);
+
+impl CompleterHandle {
+    /// [Complete][Context::complete] the originating context (and any derived children).
+    #[track_caller]
+    pub fn complete(&self) {
+        self.0.complete_with(Cause::Completed, Source::Local);
+    }
+}
+
+/// A handle to the local completion state of a [Context].
+///
+/// A handle ignores the parent chain: it doesn't keep the parents of its originating context
+/// alive, though it observes their completion once propagated to the local state. It is
+/// intended as a minimal building block for custom wrappers.
+#[derive(Clone)]
+pub struct Handle(Arc<Inner>);
+
+impl Handle {
+    /// [Complete][Context::complete] the originating context (and any derived children).
+    #[track_caller]
+    pub fn complete(&self) {
+        self.0.complete_with(Cause::Completed, Source::Local);
+    }
+
+    /// Returns true if the originating context has been completed locally.
+    pub fn is_completed(&self) -> bool {
+        self.0.is_completed()
+    }
+
+    /// Register a waker to be woken when the originating context is completed locally.
+    ///
+    /// Wakers are woken in the order they were registered. Wakers registered after completion
+    /// are never woken, so callers should check
+    /// [is_completed][Handle::is_completed] after registering.
+    pub fn register(&self, waker: &Waker) {
+        self.0.wake.register(waker);
+    }
+}
+
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// The current time according to the tokio clock, which may be paused in tests.
+#[cfg(feature = "tokio")]
+fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+#[cfg(not(feature = "tokio"))]
+fn now() -> Instant {
+    Instant::now()
+}
+
+/// Complete `inner` with [Cause::Deadline] once its deadline lapses, exiting early if it
+/// completes for any other reason or is dropped. A cleared deadline waits to be re-armed.
+#[cfg(feature = "tokio")]
+async fn enforce_deadline(inner: Weak<Inner>) {
+    let rearm = match inner.upgrade() {
+        Some(inner) => inner.rearm.clone(),
+        None => return,
+    };
+
+    loop {
+        // only the local state is held while waiting, so dropping the context ends the task
+        let notified = rearm.notified();
+        let (sleep, mut completion) = match inner.upgrade() {
+            Some(inner) => {
+                let sleep = match inner.deadline() {
+                    Some(at) if at > now() => Some(tokio::time::sleep_until(at.into())),
+                    Some(_) => {
+                        inner.complete_with(Cause::Deadline, Source::Local);
+                        return;
+                    }
+                    None => None,
+                };
+                let completion = WeakCompletion {
+                    inner: Arc::downgrade(&inner),
+                    key: None,
+                };
+                (sleep, completion)
+            }
+            None => return,
+        };
+        let (mut notified, mut sleep) = (Box::pin(notified), Box::pin(sleep));
+
+        let finished = std::future::poll_fn(|cx| {
+            if Pin::new(&mut completion).poll(cx).is_ready() {
+                return Poll::Ready(true);
+            }
+            if notified.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(false);
+            }
+            match sleep.as_mut().as_pin_mut().map(|sleep| sleep.poll(cx)) {
+                Some(Poll::Ready(())) => Poll::Ready(false),
+                _ => Poll::Pending,
+            }
+        })
+        .await;
+
+        if finished {
+            return;
+        }
+    }
+}
+
+/// The error returned by [completed_for][Context::completed_for] if the context didn't
+/// complete in time.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elapsed(());
+
+#[cfg(feature = "tokio")]
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("context did not complete in time")
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl std::error::Error for Elapsed {}
+
+/// A handle to renew the deadline of a context created with [budget][Context::budget].
+#[cfg(feature = "tokio")]
+pub struct BudgetHandle(Context);
+
+#[cfg(feature = "tokio")]
+impl BudgetHandle {
+    /// Push the deadline of the budgeted context out to `dur` from now.
+    ///
+    /// Has no effect if the context has already completed.
+    pub fn renew(&self, dur: std::time::Duration) {
+        self.0.set_deadline(now() + dur);
+    }
+
+    /// [Complete][Context::complete] the budgeted context now.
+    #[track_caller]
+    pub fn cancel(&self) {
+        self.0.complete();
+    }
+}
+
+/// The outcome of [completed_weak][Context::completed_weak].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeakOutcome {
+    /// The context completed.
+    Completed,
+    /// The context was dropped before completing.
+    Dropped,
+}
+
+/// The outcome of [try_complete_with_timeout][Context::try_complete_with_timeout].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryComplete {
+    /// This call completed the context at or before its deadline.
+    WonBeforeDeadline,
+    /// This call completed the context after its deadline had passed.
+    WonAfterDeadline,
+    /// The context was already completed.
+    AlreadyCompleted,
+}
+
+/// A RAII guard that will [complete][Context::complete] its source context when dropped.
+///
+/// Holding the guard does not prevent completion from other sources, including the drop of
+/// any other guard of the same context. Like a [Handle], a guard only retains the local state
+/// of its source context, not its parents.
+///
+/// Guards are intentionally not cloneable, as it would be ambiguous whether the first or last
+/// drop completes the context. Use a [SharedGuard] to complete on the last drop:
+///
+/// ```compile_fail
+/// let ctx = async_ctx::Context::default();
+/// let guard = ctx.guard();
+/// let other = guard.clone();
+/// ```
+pub struct Guard {
+    handle: Handle,
+    on_drop: Option<Box<dyn FnOnce() + Send>>,
+    #[cfg(feature = "tokio")]
+    timer: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        #[cfg(feature = "tokio")]
+        {
+            if let Some(timer) = self.timer.take() {
+                timer.abort();
+            }
+        }
+
+        let on_drop = self
+            .on_drop
+            .take()
+            .map(|f| panic::catch_unwind(AssertUnwindSafe(f)));
+        if on_drop.is_none() {
+            self.handle.0.complete_with(Cause::Guard, Source::Local);
+        }
+        self.handle.0.release_guard();
+
+        if let Some(Err(payload)) = on_drop {
+            if !std::thread::panicking() {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+/// A reference counted RAII guard that will [complete][Context::complete] its source context
+/// when the last shared guard of that context is dropped.
+///
+/// Holding the guard does not prevent completion from other sources.
+pub struct SharedGuard(Context);
+
+impl Clone for SharedGuard {
+    fn clone(&self) -> Self {
+        self.0.shared_guard()
+    }
+}
+
+impl Drop for SharedGuard {
+    fn drop(&mut self) {
+        if self.0.inner.shared.fetch_sub(1, Relaxed) == 1 {
+            self.0.inner.complete_with(Cause::Guard, Source::Local);
+        }
+        self.0.inner.release_guard();
+    }
+}
+
+/// A handle held by a worker of a [Context] to signal that it hasn't finished cleaning up,
+/// created by [track][Context::track].
+///
+/// Unlike a [Guard], dropping a tracker doesn't complete its context; it only releases it for
+/// the purposes of [drained][Context::drained]. Cloning a tracker tracks another worker.
+pub struct Tracker(Arc<Inner>);
+
+impl Clone for Tracker {
+    fn clone(&self) -> Self {
+        self.0.acquire_guard();
+        Tracker(self.0.clone())
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.0.release_guard();
+    }
+}
+
+/// The sole completion authority for a context, which hands out [Context] waiters.
+///
+/// Each waiter is a fresh child of the owned context, so completing a waiter only affects that
+/// waiter (and its clones). Dropping the owned context without completing it completes it
+/// with [Cause::Guard].
+///
+/// An owned context cannot be cloned:
+///
+/// ```compile_fail
+/// let owned = async_ctx::OwnedContext::default();
+/// let other = owned.clone();
+/// ```
+#[derive(Default)]
+pub struct OwnedContext(Context);
+
+impl OwnedContext {
+    /// Returns a new waiter that completes when this context completes.
+    pub fn waiter(&self) -> Context {
+        self.0.child()
+    }
+
+    /// Complete this context and all of its waiters.
+    #[track_caller]
+    pub fn complete(self) {
+        self.0.complete();
+    }
+}
+
+impl Drop for OwnedContext {
+    fn drop(&mut self) {
+        self.0.inner.complete_with(Cause::Guard, Source::Local);
+    }
+}
+
+/// A handle for supervising the children of a root context, created by
+/// [new_group][Context::new_group].
+pub struct Group {
+    root: Context,
+    children: Mutex<Vec<Weak<Inner>>>,
+}
+
+impl Group {
+    /// Derive and track a child of the root context.
+    pub fn child(&self) -> Context {
+        let chd = self.root.child();
+        let mut children = lock(&self.children);
+        if children.len() == children.capacity() {
+            children.retain(|c| c.strong_count() > 0);
+        }
+        children.push(Arc::downgrade(&chd.inner));
+        chd
+    }
+
+    /// [Complete][Context::complete] the root context, and with it every child.
+    #[track_caller]
+    pub fn complete_all(&self) {
+        self.root.complete();
+    }
+
+    /// Returns the number of tracked children that are still alive.
+    pub fn live(&self) -> usize {
+        let mut children = lock(&self.children);
+        children.retain(|c| c.strong_count() > 0);
+        children.len()
+    }
+
+    /// Returns a future that resolves once every tracked child has been dropped, along with
+    /// all of its clones, guards, and handles.
+    pub fn drained(&self) -> GroupDrained<'_> {
+        GroupDrained {
+            group: self,
+            slot: None,
+        }
+    }
+}
+
+/// A future that resolves once every tracked child of a [Group] has been dropped, created by
+/// [drained][Group::drained].
+///
+/// The future waits on one live child at a time, holding a single waker slot in it.
+pub struct GroupDrained<'a> {
+    group: &'a Group,
+    slot: Option<(Weak<Inner>, u64)>,
+}
+
+impl GroupDrained<'_> {
+    fn release(&mut self) {
+        if let Some((inner, key)) = self.slot.take() {
+            if let Some(inner) = inner.upgrade() {
+                inner.wake.remove(key);
+            }
+        }
+    }
+}
+
+impl Future for GroupDrained<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let mut children = lock(&self.group.children);
+        children.retain(|c| c.strong_count() > 0);
+        let inner = match children.iter().find_map(Weak::upgrade) {
+            Some(inner) => inner,
+            None => {
+                drop(children);
+                self.slot = None;
+                return Poll::Ready(());
+            }
+        };
+        drop(children);
+
+        // woken on completion, or on drop once completed
+        let key = match &self.slot {
+            Some((prev, key)) if prev.as_ptr() == Arc::as_ptr(&inner) => Some(*key),
+            _ => {
+                self.release();
+                None
+            }
+        };
+        let key = inner.wake.update(key, ctx.waker());
+        self.slot = Some((Arc::downgrade(&inner), key));
+        Poll::Pending
+    }
+}
+
+impl Drop for GroupDrained<'_> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// A pool that recycles the allocations of contexts created and dropped at a high rate.
+///
+/// A pooled allocation is only reused once nothing refers to the context it backed, and every
+/// reuse starts afresh with a new [id][Context::id], so recycled contexts never satisfy stale
+/// waiters. Holding a [Handle] or a [weak observer][Context::completed_weak] prevents reuse.
+///
+/// Retained allocations live as long as the pool, so weak observers of a pooled context that
+/// is released without completing only resolve once the pool is dropped.
+#[cfg(feature = "pool")]
+pub struct ContextPool {
+    slots: Mutex<Vec<Arc<Inner>>>,
+    capacity: usize,
+}
+
+#[cfg(feature = "pool")]
+impl ContextPool {
+    /// Create a pool that retains at most `capacity` allocations for reuse.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Returns a fresh root context, recycling a released allocation if one is available.
+    pub fn acquire(&self) -> Context {
+        let mut slots = lock(&self.slots);
+
+        for slot in slots.iter_mut() {
+            if let Some(inner) = Arc::get_mut(slot) {
+                *inner = Inner::default();
+                return Context {
+                    parent: None,
+                    inner: slot.clone(),
+                };
+            }
+        }
+
+        let inner = Arc::new(Inner::default());
+        if slots.len() < self.capacity {
+            slots.push(inner.clone());
+        }
+        Context {
+            parent: None,
+            inner,
+        }
+    }
+
+    /// Returns the number of allocations retained by this pool.
+    pub fn allocations(&self) -> usize {
+        lock(&self.slots).len()
+    }
+}
+
+/// Utilities for writing deterministic tests.
+#[cfg(feature = "test-util")]
+pub mod test_util {
+    use std::cell::Cell;
+
+    /// The grace period used by [assert_pending][crate::Context::assert_pending] and
+    /// [assert_completed][crate::Context::assert_completed].
+    #[cfg(feature = "tokio")]
+    pub const JIFFY: std::time::Duration = std::time::Duration::from_millis(10);
+
+    thread_local! {
+        static NEXT_ID: Cell<Option<u64>> = const { Cell::new(None) };
+    }
+
+    pub(crate) fn next_id() -> Option<u64> {
+        NEXT_ID.with(|next| {
+            let id = next.get()?;
+            next.set(Some(id + 1));
+            Some(id)
+        })
+    }
+
+    /// Assign ids to contexts created on the current thread sequentially from `id`, rather
+    /// than from the process wide counter.
+    pub fn set_next_id(id: u64) {
+        NEXT_ID.with(|next| next.set(Some(id)));
+    }
+
+    /// Revert to assigning ids from the process wide counter on the current thread.
+    pub fn clear_next_id() {
+        NEXT_ID.with(|next| next.set(None));
     }
 }
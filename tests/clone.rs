@@ -0,0 +1,52 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Clone cost tests, kept apart from the other tests as they install a counting allocator.
+#![cfg(not(loom))]
+use async_ctx::Context;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[test]
+fn clone_doesnt_allocate_at_any_depth() {
+    let root = Context::default();
+    let deep = (0..64).fold(root.child(), |ctx, _| ctx.child());
+
+    for ctx in [&root, &deep] {
+        let before = allocations();
+        let clones: Vec<_> = (0..1000).map(|_| ctx.clone()).collect();
+        // the only allocation is the vec holding the clones
+        assert_eq!(allocations() - before, 1);
+        drop(clones);
+    }
+}
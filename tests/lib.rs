@@ -4,11 +4,82 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-use async_ctx::Context;
+use async_ctx::{
+    cancelled, Cancellation, Cause, ChildPolicy, Context, CycleError, Drained, OwnedContext,
+    Source, TryComplete, WeakOutcome,
+};
+use std::{
+    future::Future,
+    mem, panic,
+    pin::Pin,
+    ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Barrier, Mutex,
+    },
+    task::{self, RawWaker, RawWakerVTable, Wake, Waker},
+    thread,
+    time::Instant,
+};
 use tokio::time::{timeout, Duration};
 
 const JIFFY: Duration = Duration::from_millis(10);
 
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+struct OrderedWaker(usize, Arc<Mutex<Vec<usize>>>);
+
+impl Wake for OrderedWaker {
+    fn wake(self: Arc<Self>) {
+        self.1.lock().unwrap().push(self.0);
+    }
+}
+
+fn panicking_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        panic!("waker clone panicked");
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+}
+
+/// A waker that counts how many times it has been cloned (i.e. registered) in `clones`.
+fn cloning_waker(clones: &'static AtomicUsize) -> Waker {
+    fn clone(data: *const ()) -> RawWaker {
+        unsafe { &*(data as *const AtomicUsize) }.fetch_add(1, Ordering::SeqCst);
+        RawWaker::new(data, &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    let data = clones as *const AtomicUsize as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(data, &VTABLE)) }
+}
+
+fn counter() -> (Arc<AtomicUsize>, impl FnOnce() + Send + 'static) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let count = calls.clone();
+
+    (calls, move || {
+        count.fetch_add(1, Ordering::SeqCst);
+    })
+}
+
+impl CountingWaker {
+    fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 #[tokio::test]
 async fn is_pending_if_not_completed() {
     let ctx = Context::default();
@@ -67,3 +138,2623 @@ async fn child_completion_doesnt_propagate_to_parent() {
     fst.await.unwrap();
     par.await.unwrap_err();
 }
+
+#[test]
+fn try_complete_without_deadline_wins_before_deadline() {
+    let ctx = Context::default();
+
+    assert_eq!(
+        ctx.try_complete_with_timeout(),
+        TryComplete::WonBeforeDeadline
+    );
+}
+
+#[test]
+fn try_complete_before_deadline() {
+    let ctx = Context::default().child_with_deadline(Instant::now() + Duration::from_secs(60));
+
+    assert_eq!(
+        ctx.try_complete_with_timeout(),
+        TryComplete::WonBeforeDeadline
+    );
+}
+
+#[test]
+fn try_complete_after_deadline() {
+    let ctx = Context::default().child_with_deadline(Instant::now());
+    thread::sleep(JIFFY);
+
+    assert_eq!(
+        ctx.try_complete_with_timeout(),
+        TryComplete::WonAfterDeadline
+    );
+}
+
+#[test]
+fn try_complete_after_parent_deadline() {
+    let par = Context::default().child_with_deadline(Instant::now());
+    let ctx = par.child_with_deadline(Instant::now() + Duration::from_secs(60));
+    thread::sleep(JIFFY);
+
+    assert_eq!(
+        ctx.try_complete_with_timeout(),
+        TryComplete::WonAfterDeadline
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn try_complete_after_deadline_follows_tokio_clock() {
+    let at = tokio::time::Instant::now().into_std() + Duration::from_secs(1);
+    let ctx = Context::default().child_with_deadline(at);
+    tokio::time::advance(Duration::from_secs(2)).await;
+
+    assert_eq!(
+        ctx.try_complete_with_timeout(),
+        TryComplete::WonAfterDeadline
+    );
+}
+
+#[test]
+fn try_complete_if_already_completed() {
+    let ctx = Context::default();
+    ctx.complete();
+
+    assert_eq!(
+        ctx.try_complete_with_timeout(),
+        TryComplete::AlreadyCompleted
+    );
+}
+
+#[test]
+fn try_complete_if_parent_completed() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    ctx.complete();
+
+    assert_eq!(
+        chd.try_complete_with_timeout(),
+        TryComplete::AlreadyCompleted
+    );
+}
+
+#[test]
+fn try_complete_race_has_one_winner() {
+    let ctx = Context::default();
+    let barrier = Arc::new(Barrier::new(2));
+
+    let racers: Vec<_> = (0..2)
+        .map(|_| {
+            let (ctx, barrier) = (ctx.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                ctx.try_complete_with_timeout()
+            })
+        })
+        .collect();
+
+    let mut outcomes: Vec<_> = racers.into_iter().map(|t| t.join().unwrap()).collect();
+    outcomes.sort_by_key(|o| *o == TryComplete::AlreadyCompleted);
+
+    assert_eq!(
+        outcomes,
+        [
+            TryComplete::WonBeforeDeadline,
+            TryComplete::AlreadyCompleted
+        ]
+    );
+}
+
+#[tokio::test]
+async fn try_complete_wakes_waiters() {
+    let ctx = Context::default();
+    let fut = timeout(JIFFY, ctx.clone());
+
+    assert!(ctx.try_complete());
+    assert!(!ctx.try_complete());
+    fut.await.unwrap();
+}
+
+#[tokio::test]
+async fn is_pending_if_shared_guard_is_live() {
+    let ctx = Context::default();
+    let guard = ctx.shared_guard();
+    let _clone = guard.clone();
+    drop(guard);
+
+    timeout(JIFFY, ctx).await.unwrap_err();
+}
+
+#[tokio::test]
+async fn is_ready_if_all_shared_guards_are_dropped() {
+    let ctx = Context::default();
+    let guard = ctx.shared_guard();
+    let clone = guard.clone();
+    let other = ctx.shared_guard();
+    drop((guard, clone, other));
+
+    timeout(JIFFY, ctx).await.unwrap();
+}
+
+#[tokio::test]
+async fn guard_scope_completes_when_scoped_future_finishes() {
+    let ctx = Context::default();
+    let gate = Context::default();
+    let scope = tokio::spawn(ctx.guard_scope(gate.clone()));
+
+    timeout(JIFFY, ctx.clone()).await.unwrap_err();
+    gate.complete();
+    scope.await.unwrap();
+    timeout(JIFFY, ctx).await.unwrap();
+}
+
+#[tokio::test]
+async fn guard_scope_yields_scoped_output() {
+    let ctx = Context::default();
+
+    assert_eq!(ctx.guard_scope(async { 42 }).await, 42);
+    timeout(JIFFY, ctx).await.unwrap();
+}
+
+#[tokio::test]
+async fn guard_scope_completes_when_cancelled() {
+    let ctx = Context::default();
+    let scope = ctx.guard_scope(Context::default());
+
+    timeout(JIFFY, scope).await.unwrap_err();
+    timeout(JIFFY, ctx).await.unwrap();
+}
+
+#[tokio::test]
+async fn nested_guard_scopes_complete_on_outermost_exit() {
+    let ctx = Context::default();
+    let inner = ctx.clone();
+
+    ctx.guard_scope(async move {
+        inner.guard_scope(async {}).await;
+        timeout(JIFFY, inner).await.unwrap_err();
+    })
+    .await;
+
+    timeout(JIFFY, ctx).await.unwrap();
+}
+
+#[tokio::test]
+async fn handle_completion_resolves_context() {
+    let ctx = Context::default();
+    let fut = timeout(JIFFY, ctx.clone());
+    ctx.handle().complete();
+
+    fut.await.unwrap();
+    assert!(ctx.handle().is_completed());
+}
+
+#[test]
+fn handle_observes_propagated_completion() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    ctx.complete();
+
+    assert!(ctx.handle().is_completed());
+    assert!(chd.handle().is_completed());
+}
+
+#[test]
+fn handle_completion_doesnt_propagate_to_parent() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    chd.handle().complete();
+
+    assert!(chd.handle().is_completed());
+    assert!(!ctx.handle().is_completed());
+}
+
+#[test]
+fn handle_wakes_registered_wakers() {
+    let ctx = Context::default();
+    let handle = ctx.handle();
+    let waker = Arc::new(CountingWaker::default());
+    handle.register(&Waker::from(waker.clone()));
+
+    assert_eq!(waker.count(), 0);
+    ctx.complete();
+    assert_eq!(waker.count(), 1);
+}
+
+#[test]
+fn complete_wakes_in_registration_order() {
+    let ctx = Context::default();
+    let order = Arc::new(Mutex::new(vec![]));
+
+    for i in 0..8 {
+        let waker = Waker::from(Arc::new(OrderedWaker(i, order.clone())));
+        ctx.handle().register(&waker);
+    }
+    ctx.complete();
+
+    assert_eq!(*order.lock().unwrap(), (0..8).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn cause_is_none_if_not_completed() {
+    let ctx = Context::default();
+
+    assert_eq!(ctx.cause(), None);
+    timeout(JIFFY, ctx.into_future_with_cause())
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn cause_of_explicit_completion() {
+    let ctx = Context::default();
+    let fut = timeout(JIFFY, ctx.clone().into_future_with_cause());
+    ctx.complete();
+
+    assert_eq!(fut.await.unwrap(), Cause::Completed);
+}
+
+#[tokio::test]
+async fn cause_of_guard_drop() {
+    let ctx = Context::default();
+    let fut = timeout(JIFFY, ctx.clone().into_future_with_cause());
+    drop(ctx.guard());
+
+    assert_eq!(fut.await.unwrap(), Cause::Guard);
+}
+
+#[tokio::test]
+async fn cause_of_shared_guard_drop() {
+    let ctx = Context::default();
+    let fut = timeout(JIFFY, ctx.clone().into_future_with_cause());
+    drop(ctx.shared_guard());
+
+    assert_eq!(fut.await.unwrap(), Cause::Guard);
+}
+
+#[tokio::test]
+async fn cause_is_inherited_from_parent() {
+    let ctx = Context::default();
+    let fut = timeout(JIFFY, ctx.child().into_future_with_cause());
+    drop(ctx.guard());
+
+    assert_eq!(fut.await.unwrap(), Cause::Guard);
+}
+
+#[test]
+fn cause_is_set_by_first_completion() {
+    let ctx = Context::default();
+    drop(ctx.guard());
+    ctx.complete();
+
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[test]
+fn with_id_assigns_id() {
+    let ctx = Context::with_id(42);
+
+    assert_eq!(ctx.id(), 42);
+    assert_eq!(ctx.clone().id(), 42);
+    assert_ne!(ctx.child().id(), 42);
+}
+
+#[test]
+fn debug_includes_id_and_cause() {
+    let ctx = Context::with_id(7);
+    ctx.complete();
+
+    assert_eq!(
+        format!("{:?}", ctx),
+        "Context { id: 7, cause: Some(Completed), parent: None }",
+    );
+}
+
+#[test]
+fn automatic_ids_are_unique() {
+    let ctx = Context::default();
+
+    assert_ne!(ctx.id(), Context::default().id());
+    assert_ne!(ctx.id(), ctx.child().id());
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn ids_are_deterministic_with_next_id_override() {
+    async_ctx::test_util::set_next_id(100);
+    let ctx = Context::default();
+    let chd = ctx.child();
+    async_ctx::test_util::clear_next_id();
+
+    assert_eq!((ctx.id(), chd.id()), (100, 101));
+    assert_eq!(
+        format!("{:?}", chd),
+        "Context { id: 101, cause: None, parent: Some(Context { id: 100, cause: None, parent: None }) }",
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn completed_or_timeout_if_completed() {
+    let ctx = Context::default();
+    let par = ctx.clone();
+    tokio::spawn(async move { par.complete() });
+
+    assert!(ctx.completed_or_timeout(JIFFY).await);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn completed_or_timeout_if_not_completed() {
+    let ctx = Context::default();
+
+    assert!(!ctx.completed_or_timeout(JIFFY).await);
+    assert!(!ctx.handle().is_completed());
+}
+
+#[test]
+fn poisoned_wakers_still_wake() {
+    let ctx = Context::default();
+    let handle = ctx.handle();
+    let waker = Arc::new(CountingWaker::default());
+
+    panic::catch_unwind(|| handle.register(&panicking_waker())).unwrap_err();
+    handle.register(&Waker::from(waker.clone()));
+    ctx.complete();
+
+    assert_eq!(waker.count(), 1);
+    assert_eq!(ctx.cause(), Some(Cause::Completed));
+}
+
+#[test]
+fn deep_poll_registers_only_locally() {
+    let root = Context::default();
+    let mut leaf = (0..64).fold(root.child(), |ctx, _| ctx.child());
+    let waker = Arc::new(CountingWaker::default());
+    let counted = Waker::from(waker.clone());
+
+    assert!(Pin::new(&mut leaf)
+        .poll(&mut task::Context::from_waker(&counted))
+        .is_pending());
+    root.wake_all();
+    assert_eq!(waker.count(), 0);
+
+    root.complete();
+    assert_eq!(waker.count(), 1);
+}
+
+#[test]
+fn repeated_polls_from_one_task_use_one_slot() {
+    let ctx = Context::default();
+    let mut fut = ctx.clone();
+    let waker = Arc::new(CountingWaker::default());
+    let other = Arc::new(CountingWaker::default());
+
+    for _ in 0..100 {
+        let waker = Waker::from(waker.clone());
+        assert!(Pin::new(&mut fut)
+            .poll(&mut task::Context::from_waker(&waker))
+            .is_pending());
+    }
+    let other_waker = Waker::from(other.clone());
+    assert!(Pin::new(&mut ctx.clone())
+        .poll(&mut task::Context::from_waker(&other_waker))
+        .is_pending());
+
+    assert_eq!(ctx.complete_reporting(), Some(2));
+    assert_eq!(waker.count(), 1);
+    assert_eq!(other.count(), 1);
+}
+
+#[test]
+fn wait_blocks_until_completed() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let fired = Arc::new(AtomicUsize::new(0));
+    let counter = fired.clone();
+    chd.on_complete(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+
+    let completer = thread::spawn(move || {
+        thread::sleep(JIFFY);
+        ctx.complete();
+        ctx.complete();
+    });
+    chd.wait();
+    assert!(chd.is_completed());
+    chd.wait();
+    completer.join().unwrap();
+
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn context_is_two_words() {
+    assert_eq!(mem::size_of::<Context>(), 2 * mem::size_of::<usize>());
+}
+
+#[test]
+fn clones_share_state() {
+    let at = Instant::now() + Duration::from_secs(60);
+    let ctx = Context::default().child_with_deadline(at);
+    let clone = ctx.clone();
+    ctx.complete();
+
+    assert_eq!(clone.id(), ctx.id());
+    assert_eq!(clone.deadline(), Some(at));
+    assert_eq!(clone.cause(), Some(Cause::Completed));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn spawn_tears_down_subtree_on_completion() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let tasks: Vec<_> = [&ctx, &chd, &chd.child()]
+        .iter()
+        .map(|ctx| {
+            let guard = Context::default().guard_with({
+                let dropped = dropped.clone();
+                move |_| {
+                    dropped.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            ctx.spawn(async move {
+                let _guard = guard;
+                std::future::pending::<()>().await
+            })
+        })
+        .collect();
+
+    assert_eq!(chd.spawn(async { 7 }).await.unwrap(), Ok(7));
+    ctx.complete();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), Err(Cause::Completed));
+    }
+    assert_eq!(dropped.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn drained_awaits_completion_and_trackers() {
+    let ctx = Context::default();
+    let cleaned = Arc::new(AtomicUsize::new(0));
+    let tracker = ctx.track();
+    for _ in 0..4 {
+        let (ctx, tracker, cleaned) = (ctx.child(), tracker.clone(), cleaned.clone());
+        tokio::spawn(async move {
+            ctx.await;
+            tokio::task::yield_now().await;
+            cleaned.fetch_add(1, Ordering::SeqCst);
+            drop(tracker);
+        });
+    }
+    drop(tracker);
+
+    timeout(JIFFY, ctx.drained()).await.unwrap_err();
+    ctx.complete();
+    timeout(JIFFY, ctx.drained()).await.unwrap();
+    assert_eq!(cleaned.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn drained_resolves_once_its_generation_completed() {
+    let ctx = Context::default();
+    let waker = Arc::new(CountingWaker::default());
+    let waker_ref = Waker::from(waker.clone());
+    let mut cx = task::Context::from_waker(&waker_ref);
+
+    let mut drained: Drained = ctx.drained();
+    assert!(Pin::new(&mut drained).poll(&mut cx).is_pending());
+    ctx.complete();
+    assert!(ctx.reset());
+    assert_eq!(waker.count(), 1);
+    assert!(Pin::new(&mut drained).poll(&mut cx).is_ready());
+    assert!(Pin::new(&mut ctx.drained()).poll(&mut cx).is_pending());
+}
+
+#[tokio::test]
+async fn dropping_tracker_doesnt_complete() {
+    let ctx = Context::default();
+    drop(ctx.track());
+
+    assert!(!ctx.is_completed());
+    timeout(JIFFY, ctx.drain_guards()).await.unwrap();
+}
+
+#[tokio::test]
+async fn drain_guards_is_ready_without_guards() {
+    let ctx = Context::default();
+
+    timeout(JIFFY, ctx.drain_guards()).await.unwrap();
+}
+
+#[tokio::test]
+async fn drain_guards_is_pending_while_guards_are_live() {
+    let ctx = Context::default();
+    let _guard = ctx.guard();
+    let _shared = ctx.shared_guard();
+
+    timeout(JIFFY, ctx.drain_guards()).await.unwrap_err();
+}
+
+#[tokio::test]
+async fn drain_guards_is_ready_when_guards_are_dropped() {
+    let ctx = Context::default();
+    let guard = ctx.guard();
+    let shared = ctx.shared_guard();
+    let clone = shared.clone();
+    let drain = ctx.drain_guards();
+
+    drop((guard, shared));
+    timeout(JIFFY, ctx.clone()).await.unwrap();
+    drop(clone);
+    timeout(JIFFY, drain).await.unwrap();
+}
+
+#[tokio::test]
+async fn drain_guards_is_independent_of_completion() {
+    let ctx = Context::default();
+    let shared = ctx.shared_guard();
+    let drain = ctx.drain_guards();
+    ctx.complete();
+
+    timeout(JIFFY, ctx.drain_guards()).await.unwrap_err();
+    drop(shared);
+    timeout(JIFFY, drain).await.unwrap();
+}
+
+#[tokio::test]
+async fn drain_guards_ignores_guards_issued_after_drain() {
+    let ctx = Context::default();
+    let early = ctx.drain_guards();
+    let guard = ctx.guard();
+    let drain = ctx.drain_guards();
+    drop(guard);
+    let _late = ctx.guard();
+
+    timeout(JIFFY, early).await.unwrap();
+    timeout(JIFFY, drain).await.unwrap();
+    timeout(JIFFY, ctx.drain_guards()).await.unwrap_err();
+}
+
+#[test]
+fn source_of_local_completion() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+
+    assert_eq!(chd.source(), None);
+    chd.complete();
+    assert_eq!(chd.source(), Some(Source::Local));
+    assert_eq!(ctx.source(), None);
+}
+
+#[test]
+fn source_of_parent_completion() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    ctx.complete();
+
+    assert_eq!(chd.source(), Some(Source::Parent));
+}
+
+#[test]
+fn cause_and_source_matches_require_wildcard() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    drop(ctx.guard());
+
+    let cause = match chd.cause().unwrap() {
+        Cause::Completed => "completed",
+        Cause::Guard => "guard",
+        Cause::Deadline => "deadline",
+        _ => "unknown",
+    };
+    let source = match chd.source().unwrap() {
+        Source::Local => "local",
+        Source::Parent => "parent",
+        _ => "unknown",
+    };
+
+    assert_eq!((cause, source), ("guard", "parent"));
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn completed_shared_resolves_all_clones() {
+    let ctx = Context::default();
+    let shared = ctx.completed_shared();
+    let tasks: Vec<_> = (0..8)
+        .map(|_| tokio::spawn(timeout(JIFFY, shared.clone())))
+        .collect();
+    ctx.complete();
+
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn completed_shared_is_pending_if_not_completed() {
+    let ctx = Context::default();
+
+    timeout(JIFFY, ctx.completed_shared()).await.unwrap_err();
+}
+
+#[test]
+fn on_complete_runs_once_on_completion() {
+    let ctx = Context::default();
+    let (calls, callback) = counter();
+    ctx.on_complete(callback);
+
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+    ctx.complete();
+    ctx.complete();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn on_complete_runs_immediately_if_completed() {
+    let ctx = Context::default();
+    let (calls, callback) = counter();
+    ctx.complete();
+    ctx.on_complete(callback);
+
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn on_complete_runs_for_children_after_parent() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let grandchild = chd.child();
+    let order = Arc::new(Mutex::new(vec![]));
+
+    for (name, ctx) in [
+        ("grandchild", &grandchild),
+        ("child", &chd),
+        ("parent", &ctx),
+    ] {
+        let order = order.clone();
+        ctx.on_complete(move || order.lock().unwrap().push(name));
+    }
+    ctx.complete();
+
+    assert_eq!(*order.lock().unwrap(), ["parent", "child", "grandchild"]);
+    assert_eq!(grandchild.source(), Some(Source::Parent));
+    assert_eq!(grandchild.cause(), Some(Cause::Completed));
+}
+
+#[test]
+fn on_complete_doesnt_run_for_parent_on_child_completion() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let (calls, callback) = counter();
+    ctx.on_complete(callback);
+    chd.complete();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn children_of_completed_parent_are_completed() {
+    let ctx = Context::default();
+    ctx.complete();
+    let chd = ctx.child();
+
+    assert_eq!(chd.source(), Some(Source::Parent));
+    assert!(chd.handle().is_completed());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn budget_completes_if_not_renewed() {
+    let ctx = Context::default();
+    let (chd, _budget) = ctx.budget(JIFFY * 3);
+
+    timeout(JIFFY * 2, chd.clone()).await.unwrap_err();
+    timeout(JIFFY * 2, chd.clone().into_future_with_cause())
+        .await
+        .map(|cause| assert_eq!(cause, Cause::Deadline))
+        .unwrap();
+    assert_eq!(ctx.cause(), None);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn budget_is_pending_while_renewed() {
+    let (ctx, budget) = Context::default().budget(JIFFY * 3);
+
+    for _ in 0..10 {
+        timeout(JIFFY * 2, ctx.clone()).await.unwrap_err();
+        budget.renew(JIFFY * 3);
+    }
+
+    assert_eq!(ctx.cause(), None);
+    timeout(JIFFY * 4, ctx).await.unwrap();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn budget_renewal_can_shorten_deadline() {
+    let (ctx, budget) = Context::default().budget(Duration::from_secs(60));
+    budget.renew(JIFFY);
+
+    assert_eq!(
+        timeout(JIFFY * 2, ctx.into_future_with_cause())
+            .await
+            .unwrap(),
+        Cause::Deadline,
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn budget_cancel_completes_now() {
+    let (ctx, budget) = Context::default().budget(Duration::from_secs(60));
+    budget.cancel();
+
+    assert_eq!(
+        timeout(JIFFY, ctx.into_future_with_cause()).await.unwrap(),
+        Cause::Completed,
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn with_timeout_completes_with_deadline() {
+    let ctx = Context::with_timeout(JIFFY * 3);
+    let at = ctx.deadline().unwrap();
+
+    timeout(JIFFY * 2, ctx.clone()).await.unwrap_err();
+    assert_eq!(
+        timeout(JIFFY * 2, ctx.into_future_with_cause())
+            .await
+            .unwrap(),
+        Cause::Deadline,
+    );
+    assert_eq!(Context::with_deadline(at).cause(), Some(Cause::Deadline));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn dropped_timeout_context_is_released() {
+    let ctx = Context::with_timeout(Duration::from_secs(3600));
+    let chd = ctx.child_with_timeout(Duration::from_secs(3600));
+    let weak = ctx.completed_weak();
+    let weak_chd = chd.completed_weak();
+    drop(chd);
+    tokio::task::yield_now().await;
+    drop(ctx);
+
+    assert_eq!(timeout(JIFFY, weak).await.unwrap(), WeakOutcome::Dropped);
+    assert_eq!(
+        timeout(JIFFY, weak_chd).await.unwrap(),
+        WeakOutcome::Dropped
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn child_with_timeout_yields_to_earlier_completion() {
+    let ctx = Context::default();
+    let chd = ctx.child_with_timeout(Duration::from_secs(60));
+    ctx.complete();
+
+    timeout(JIFFY, chd.clone()).await.unwrap();
+    assert_eq!(chd.cause(), Some(Cause::Completed));
+
+    let chd = Context::default().child_with_timeout(JIFFY);
+    timeout(JIFFY * 2, chd.clone()).await.unwrap();
+    assert_eq!(chd.source(), Some(Source::Local));
+}
+
+#[test]
+fn complete_with_error_propagates_to_children() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let cause = Cause::error("upstream failed");
+    ctx.complete_with(cause.clone());
+    ctx.complete_with(Cause::Deadline);
+
+    assert_eq!(ctx.cause(), Some(cause.clone()));
+    assert_eq!(chd.cause(), Some(cause.clone()));
+    assert_eq!(chd.source(), Some(Source::Parent));
+    assert_ne!(Some(cause), Some(Cause::error("upstream failed")));
+    match chd.cause() {
+        Some(Cause::Error(err)) => assert_eq!(err.to_string(), "upstream failed"),
+        cause => panic!("unexpected cause: {:?}", cause),
+    }
+}
+
+#[test]
+fn set_deadline_in_past_completes_now() {
+    let ctx = Context::default();
+    let at = Instant::now();
+    ctx.set_deadline(at);
+
+    assert_eq!(ctx.deadline(), Some(at));
+    assert_eq!(ctx.cause(), Some(Cause::Deadline));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn set_deadline_extends_budget() {
+    let (ctx, _budget) = Context::default().budget(JIFFY);
+    ctx.set_deadline(tokio::time::Instant::now().into_std() + JIFFY * 5);
+
+    timeout(JIFFY * 3, ctx.clone()).await.unwrap_err();
+    assert_eq!(
+        timeout(JIFFY * 3, ctx.into_future_with_cause())
+            .await
+            .unwrap(),
+        Cause::Deadline,
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn clear_deadline_disarms_budget() {
+    let (ctx, _budget) = Context::default().budget(JIFFY);
+    ctx.clear_deadline();
+
+    timeout(JIFFY * 3, ctx.clone()).await.unwrap_err();
+    assert_eq!(ctx.deadline(), None);
+
+    ctx.set_deadline(tokio::time::Instant::now().into_std() + JIFFY);
+    timeout(JIFFY * 2, ctx).await.unwrap();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn budget_completes_with_parent() {
+    let ctx = Context::default();
+    let (chd, _budget) = ctx.budget(Duration::from_secs(60));
+    ctx.complete();
+
+    timeout(JIFFY, chd.clone()).await.unwrap();
+    assert_eq!(chd.source(), Some(Source::Parent));
+}
+
+#[tokio::test]
+async fn orphan_doesnt_observe_former_parent() {
+    let ctx = Context::default();
+    let chd = ctx.child_with_deadline(Instant::now() + Duration::from_secs(60));
+    let orphan = chd.orphan();
+    ctx.complete();
+
+    assert_eq!(orphan.id(), chd.id());
+    assert_eq!(orphan.deadline(), chd.deadline());
+    timeout(JIFFY, orphan.clone()).await.unwrap_err();
+    timeout(JIFFY, chd.clone()).await.unwrap_err();
+    assert_eq!(chd.cause(), None);
+}
+
+#[tokio::test]
+async fn orphan_can_complete_locally() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let grandchild = chd.child();
+    let orphan = chd.orphan();
+    orphan.complete();
+
+    timeout(JIFFY, chd).await.unwrap();
+    timeout(JIFFY, grandchild).await.unwrap();
+    timeout(JIFFY, ctx).await.unwrap_err();
+}
+
+#[test]
+fn orphan_of_completed_child_is_completed() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    ctx.complete();
+
+    assert_eq!(chd.orphan().cause(), Some(Cause::Completed));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn watch_observes_completion() {
+    let ctx = Context::default();
+    let mut rx = ctx.watch();
+    let mut cloned = rx.clone();
+    assert!(!*rx.borrow());
+
+    let task = tokio::spawn(async move { cloned.changed().await.map(|_| *cloned.borrow()) });
+    timeout(JIFFY, rx.changed()).await.unwrap_err();
+    ctx.child().complete();
+    assert!(!*rx.borrow());
+
+    ctx.complete();
+    assert!(*rx.borrow());
+    assert!(task.await.unwrap().unwrap());
+    assert!(*ctx.watch().borrow());
+}
+
+#[cfg(feature = "tokio-util")]
+#[tokio::test]
+async fn token_bridges_complete_both_ways() {
+    use tokio_util::sync::CancellationToken;
+
+    let token = CancellationToken::new();
+    let ctx = Context::from_token(token.child_token());
+    let chd = ctx.child();
+    let mirrored = chd.to_token();
+    timeout(JIFFY, ctx.clone()).await.unwrap_err();
+
+    token.cancel();
+    timeout(JIFFY, ctx.clone()).await.unwrap();
+    assert_eq!(ctx.cause(), Some(Cause::Completed));
+    timeout(JIFFY, mirrored.cancelled()).await.unwrap();
+
+    assert!(Context::from_token(token).is_completed());
+    let ctx = Context::default();
+    let mirrored = ctx.to_token();
+    mirrored.cancel();
+    assert!(!ctx.is_completed());
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn abort_registration_aborts_on_completion() {
+    use futures::future::{Abortable, Aborted};
+
+    let ctx = Context::default();
+    let fut = Abortable::new(std::future::pending::<()>(), ctx.to_abort_registration());
+    let task = tokio::spawn(fut);
+    timeout(JIFFY, ctx.clone()).await.unwrap_err();
+
+    ctx.complete();
+    assert_eq!(task.await.unwrap(), Err(Aborted));
+}
+
+#[tokio::test]
+async fn child_policies_under_completed_parent() {
+    let ctx = Context::default();
+    let propagate = ctx.child_with_policy(ChildPolicy::Propagate);
+    let detached = ctx.child_with_policy(ChildPolicy::Detached);
+    let weak = ctx.child_with_policy(ChildPolicy::Weak);
+    ctx.complete();
+
+    timeout(JIFFY, propagate.clone()).await.unwrap();
+    timeout(JIFFY, weak.clone()).await.unwrap();
+    timeout(JIFFY, detached.clone()).await.unwrap_err();
+    assert_eq!(propagate.source(), Some(Source::Parent));
+    assert_eq!(weak.source(), Some(Source::Parent));
+    assert_eq!(detached.cause(), None);
+}
+
+#[tokio::test]
+async fn weak_child_doesnt_keep_parent_alive() {
+    let ctx = Context::default();
+    let propagate = ctx.child_with_policy(ChildPolicy::Propagate);
+    let parent = ctx.completed_weak();
+    drop(ctx);
+    timeout(JIFFY, parent).await.unwrap_err();
+    drop(propagate);
+
+    let ctx = Context::default();
+    let weak = ctx.child_with_policy(ChildPolicy::Weak);
+    let parent = ctx.completed_weak();
+    drop(ctx);
+    assert_eq!(timeout(JIFFY, parent).await.unwrap(), WeakOutcome::Dropped);
+    assert!(!weak.is_completed());
+}
+
+#[test]
+fn orphaned_weak_child_isnt_completed_by_parent() {
+    let ctx = Context::default();
+    let weak = ctx.child_with_policy(ChildPolicy::Weak);
+    let orphan = weak.orphan();
+    ctx.complete();
+
+    assert!(!orphan.is_completed());
+    assert!(!weak.is_completed());
+    drop(ctx);
+    assert!(weak.orphan().ancestors().next().is_none());
+}
+
+#[test]
+fn weak_child_copies_parent_deadline() {
+    let at = Instant::now() + Duration::from_secs(60);
+    let ctx = Context::default().child_with_deadline(at);
+
+    assert_eq!(
+        ctx.child_with_policy(ChildPolicy::Weak).deadline(),
+        Some(at)
+    );
+    assert_eq!(
+        ctx.child_with_policy(ChildPolicy::Detached).deadline(),
+        None
+    );
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn completed_boxed_can_be_joined() {
+    let ctxs: Vec<_> = (0..4).map(|_| Context::default()).collect();
+    let futs: Vec<_> = ctxs.iter().map(Context::completed_boxed).collect();
+    let all = timeout(JIFFY, futures::future::join_all(futs));
+    ctxs.iter().for_each(Context::complete);
+
+    all.await.unwrap();
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn completed_boxed_is_pending_until_all_complete() {
+    let ctxs: Vec<_> = (0..4).map(|_| Context::default()).collect();
+    let futs: Vec<_> = ctxs.iter().map(Context::completed_boxed).collect();
+    ctxs[1..].iter().for_each(Context::complete);
+
+    timeout(JIFFY, futures::future::join_all(futs))
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn owned_context_completes_waiters() {
+    let owned = OwnedContext::default();
+    let waiters: Vec<_> = (0..4).map(|_| owned.waiter()).collect();
+
+    timeout(JIFFY, waiters[0].clone()).await.unwrap_err();
+    owned.complete();
+
+    for waiter in waiters {
+        assert_eq!(waiter.cause(), Some(Cause::Completed));
+        timeout(JIFFY, waiter).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn owned_context_waiters_cant_complete_owner() {
+    let owned = OwnedContext::default();
+    let (fst, snd) = (owned.waiter(), owned.waiter());
+    fst.complete();
+
+    timeout(JIFFY, fst).await.unwrap();
+    timeout(JIFFY, snd).await.unwrap_err();
+}
+
+#[test]
+fn owned_context_completes_on_drop() {
+    let owned = OwnedContext::default();
+    let waiter = owned.waiter();
+    drop(owned);
+
+    assert_eq!(waiter.cause(), Some(Cause::Guard));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn completed_for_if_completed() {
+    let ctx = Context::default();
+    let par = ctx.clone();
+    tokio::spawn(async move { par.complete() });
+
+    ctx.completed_for(JIFFY).await.unwrap();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn completed_for_if_elapsed() {
+    let ctx = Context::default();
+    let err = ctx.completed_for(JIFFY).await.unwrap_err();
+
+    assert_eq!(err.to_string(), "context did not complete in time");
+    assert!(!ctx.handle().is_completed());
+}
+
+#[test]
+fn guard_completes_on_first_drop() {
+    let ctx = Context::default();
+    let fst = ctx.guard();
+    let _snd = ctx.guard();
+    drop(fst);
+
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[test]
+fn shared_guard_completes_on_last_drop() {
+    let ctx = Context::default();
+    let fst = ctx.shared_guard();
+    let snd = fst.clone();
+    drop(fst);
+
+    assert_eq!(ctx.cause(), None);
+    drop(snd);
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[test]
+fn wake_all_wakes_each_waiter_once_without_completing() {
+    let ctx = Context::default();
+    let wakers: Vec<_> = (0..3).map(|_| Arc::new(CountingWaker::default())).collect();
+    for waker in &wakers {
+        ctx.handle().register(&Waker::from(waker.clone()));
+    }
+
+    ctx.wake_all();
+    ctx.wake_all();
+
+    assert!(wakers.iter().all(|w| w.count() == 1));
+    assert_eq!(ctx.cause(), None);
+}
+
+#[tokio::test]
+async fn wake_all_leaves_waiters_pending() {
+    let ctx = Context::default();
+    let waiter = tokio::spawn(timeout(JIFFY * 5, ctx.clone()));
+    tokio::task::yield_now().await;
+    ctx.wake_all();
+
+    waiter.await.unwrap().unwrap_err();
+}
+
+#[tokio::test]
+async fn try_with_parent_propagates_parent_completion() {
+    let ctx = Context::default();
+    let other = Context::default();
+    let chd = ctx.try_with_parent(&other).unwrap();
+    other.complete();
+
+    assert_eq!(chd.id(), ctx.id());
+    timeout(JIFFY, ctx.clone()).await.unwrap();
+    assert_eq!(chd.source(), Some(Source::Parent));
+}
+
+#[test]
+fn try_with_parent_keeps_existing_parent() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    chd.try_with_parent(&Context::default()).unwrap();
+    ctx.complete();
+
+    assert_eq!(chd.source(), Some(Source::Parent));
+}
+
+#[test]
+fn try_with_parent_rejects_cycles() {
+    let ctx = Context::default();
+    let grandchild = ctx.child().child();
+
+    let err: CycleError = ctx.try_with_parent(&grandchild).unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        "context cannot be parented under itself or a descendant",
+    );
+    assert!(ctx.try_with_parent(&ctx).is_err());
+    grandchild.complete();
+    assert_eq!(ctx.cause(), None);
+
+    let (a, b) = (Context::default(), Context::default());
+    let _b2 = b.try_with_parent(&a).unwrap();
+    assert!(a.try_with_parent(&b).is_err());
+    b.complete();
+    assert_eq!(a.cause(), None);
+}
+
+#[test]
+fn try_with_parent_after_orphan() {
+    let ctx = Context::default();
+    let at = Instant::now() + Duration::from_secs(60);
+    let other = Context::default().child_with_deadline(at);
+    let orphan = ctx.child().orphan();
+    let chd = orphan.try_with_parent(&other).unwrap();
+
+    assert_eq!(chd.deadline(), Some(at));
+    ctx.complete();
+    assert_eq!(orphan.cause(), None);
+    other.complete();
+    assert_eq!(orphan.source(), Some(Source::Parent));
+}
+
+#[test]
+fn polling_completed_context_doesnt_register_wakers() {
+    static CLONES: AtomicUsize = AtomicUsize::new(0);
+    let waker = cloning_waker(&CLONES);
+    let mut cx = task::Context::from_waker(&waker);
+    let mut ctx = Context::default().child();
+    ctx.complete();
+
+    for _ in 0..1000 {
+        assert!(Pin::new(&mut ctx).poll(&mut cx).is_ready());
+    }
+    assert_eq!(CLONES.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn polling_child_of_completed_parent_doesnt_register_wakers() {
+    static CLONES: AtomicUsize = AtomicUsize::new(0);
+    let waker = cloning_waker(&CLONES);
+    let mut cx = task::Context::from_waker(&waker);
+    let ctx = Context::default();
+    let mut chd = ctx.child().child();
+    ctx.complete();
+
+    assert!(Pin::new(&mut chd).poll(&mut cx).is_ready());
+    assert_eq!(CLONES.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn polling_pending_context_registers_wakers() {
+    static CLONES: AtomicUsize = AtomicUsize::new(0);
+    let waker = cloning_waker(&CLONES);
+    let mut cx = task::Context::from_waker(&waker);
+    let mut ctx = Context::default();
+
+    assert!(Pin::new(&mut ctx).poll(&mut cx).is_pending());
+    assert_eq!(CLONES.load(Ordering::SeqCst), 1);
+    ctx.complete();
+    assert!(Pin::new(&mut ctx).poll(&mut cx).is_ready());
+    assert_eq!(CLONES.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn min_deadline_with_both_deadlines() {
+    let at = Instant::now() + Duration::from_secs(60);
+    let ctx = Context::default().child_with_deadline(at);
+    let other = Context::default().child_with_deadline(at + JIFFY);
+
+    assert_eq!(ctx.min_deadline_with(&other), Some(at));
+    assert_eq!(other.min_deadline_with(&ctx), Some(at));
+}
+
+#[test]
+fn min_deadline_with_one_deadline() {
+    let at = Instant::now() + Duration::from_secs(60);
+    let ctx = Context::default().child_with_deadline(at);
+    let other = Context::default();
+
+    assert_eq!(ctx.min_deadline_with(&other), Some(at));
+    assert_eq!(other.min_deadline_with(&ctx), Some(at));
+}
+
+#[test]
+fn min_deadline_with_no_deadlines() {
+    let ctx = Context::default();
+
+    assert_eq!(ctx.min_deadline_with(&Context::default()), None);
+}
+
+#[test]
+fn complete_reporting_counts_woken_waiters() {
+    let ctx = Context::default();
+    let wakers: Vec<_> = (0..5).map(|_| Arc::new(CountingWaker::default())).collect();
+    for waker in &wakers {
+        ctx.handle().register(&Waker::from(waker.clone()));
+    }
+
+    assert_eq!(ctx.complete_reporting(), Some(5));
+    assert!(wakers.iter().all(|w| w.count() == 1));
+}
+
+#[test]
+fn complete_reporting_only_reports_first_completion() {
+    let ctx = Context::default();
+    ctx.handle()
+        .register(&Waker::from(Arc::new(CountingWaker::default())));
+
+    assert_eq!(ctx.complete_reporting(), Some(1));
+    assert_eq!(ctx.complete_reporting(), None);
+}
+
+#[tokio::test]
+async fn complete_reporting_counts_waiting_tasks() {
+    let ctx = Context::default();
+    let tasks: Vec<_> = (0..3).map(|_| tokio::spawn(ctx.clone())).collect();
+    tokio::task::yield_now().await;
+
+    assert_eq!(ctx.complete_reporting(), Some(3));
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+#[test]
+fn exited_tasks_dont_accumulate_waker_slots() {
+    let ctx = Context::default();
+    let wakers: Vec<_> = (0..1000)
+        .map(|_| Arc::new(CountingWaker::default()))
+        .collect();
+    for waker in &wakers {
+        let waker = Waker::from(waker.clone());
+        let mut fut = ctx.clone();
+        assert!(Pin::new(&mut fut)
+            .poll(&mut task::Context::from_waker(&waker))
+            .is_pending());
+    }
+
+    assert!(ctx.complete_reporting().unwrap() <= 64);
+    // every task was woken once, either by eviction or by completion
+    assert!(wakers.iter().all(|w| w.count() == 1));
+}
+
+#[test]
+fn waiting_tasks_survive_waker_slot_eviction() {
+    let ctx = Context::default();
+    let waiting = Arc::new(CountingWaker::default());
+    let mut fut = ctx.clone();
+    let mut poll = |waker: &Waker| {
+        Pin::new(&mut fut)
+            .poll(&mut task::Context::from_waker(waker))
+            .is_pending()
+    };
+    assert!(poll(&Waker::from(waiting.clone())));
+    for _ in 0..1000 {
+        assert!(poll(&Waker::from(Arc::new(CountingWaker::default()))));
+        if waiting.count() > 0 {
+            break;
+        }
+    }
+    assert_eq!(waiting.count(), 1);
+
+    // the evicted task polls again on wakeup, and is woken by completion
+    assert!(poll(&Waker::from(waiting.clone())));
+    ctx.complete();
+    assert_eq!(waiting.count(), 2);
+}
+
+#[tokio::test]
+async fn cancellation_can_be_awaited_as_trait_object() {
+    let ctx = Context::default();
+    let dynamic: &dyn Cancellation = &ctx.child();
+
+    assert!(!dynamic.is_cancelled());
+    timeout(JIFFY, cancelled(dynamic)).await.unwrap_err();
+    ctx.complete();
+    assert!(dynamic.is_cancelled());
+    timeout(JIFFY, cancelled(dynamic)).await.unwrap();
+}
+
+#[tokio::test]
+async fn completion_can_be_awaited_as_cancellation() {
+    let ctx = Context::default();
+    let completion = ctx.completed();
+    let dynamic: &dyn Cancellation = &completion;
+
+    assert!(!dynamic.is_cancelled());
+    timeout(JIFFY, cancelled(dynamic)).await.unwrap_err();
+    ctx.complete();
+    assert!(ctx.reset());
+    assert!(dynamic.is_cancelled());
+    timeout(JIFFY, cancelled(dynamic)).await.unwrap();
+    assert!(!ctx.completed().is_cancelled());
+}
+
+#[tokio::test]
+async fn cancellation_can_be_used_generically() {
+    async fn wait(c: impl Cancellation) -> bool {
+        cancelled(&c).await;
+        c.is_cancelled()
+    }
+
+    let ctx = Context::default();
+    let fut = timeout(JIFFY, wait(ctx.clone()));
+    ctx.complete();
+
+    assert!(fut.await.unwrap());
+}
+
+#[tokio::test]
+async fn race_with_future_if_future_wins() {
+    let ctx = Context::default();
+
+    assert_eq!(ctx.race_with_future(async { 42 }).await, Ok(42));
+    assert_eq!(ctx.cause(), None);
+}
+
+#[tokio::test]
+async fn race_with_future_if_context_wins() {
+    let ctx = Context::default();
+    let par = ctx.clone();
+    tokio::spawn(async move { par.complete() });
+
+    assert_eq!(
+        ctx.race_with_future(Context::default()).await,
+        Err(Cause::Completed),
+    );
+}
+
+#[tokio::test]
+async fn race_with_future_if_already_completed() {
+    let ctx = Context::default();
+    drop(ctx.guard());
+
+    assert_eq!(ctx.race_with_future(async { 42 }).await, Err(Cause::Guard));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn race_with_future_if_deadline_wins() {
+    let (ctx, _budget) = Context::default().budget(JIFFY);
+
+    assert_eq!(
+        ctx.race_with_future(tokio::time::sleep(JIFFY * 2)).await,
+        Err(Cause::Deadline),
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn guard_for_completes_on_drop() {
+    let ctx = Context::default();
+    let guard = ctx.guard_for(JIFFY * 2);
+
+    timeout(JIFFY, ctx.clone()).await.unwrap_err();
+    drop(guard);
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn guard_for_completes_on_timeout() {
+    let ctx = Context::default();
+    let _guard = ctx.guard_for(JIFFY);
+
+    assert_eq!(
+        timeout(JIFFY * 2, ctx.into_future_with_cause())
+            .await
+            .unwrap(),
+        Cause::Deadline,
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn guard_for_timer_is_cancelled_on_drop() {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let ctx = Context::default();
+    let guard = ctx.guard_for(JIFFY);
+
+    assert_eq!(metrics.num_alive_tasks(), 1);
+    drop(guard);
+    tokio::task::yield_now().await;
+    assert_eq!(metrics.num_alive_tasks(), 0);
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[tokio::test]
+async fn run_until_if_future_wins() {
+    let ctx = Context::default();
+
+    assert_eq!(ctx.run_until(async { 42 }).await, Some(42));
+}
+
+#[tokio::test]
+async fn run_until_if_context_wins() {
+    let ctx = Context::default();
+    let fut = ctx.run_until(Context::default());
+    ctx.complete();
+
+    assert_eq!(fut.await, None);
+}
+
+#[tokio::test]
+async fn until_extension_yields_output() {
+    use async_ctx::prelude::*;
+
+    let ctx = Context::default();
+
+    assert_eq!(async { 42 }.until(&ctx).await, Some(42));
+    assert_eq!(async { 42 }.until_err(&ctx).await, Ok(42));
+}
+
+#[tokio::test]
+async fn until_extension_yields_on_completion() {
+    use async_ctx::prelude::*;
+
+    let ctx = Context::default();
+    let par = ctx.clone();
+    tokio::spawn(async move { drop(par.guard()) });
+
+    assert_eq!(Context::default().until(&ctx).await, None);
+    assert_eq!(Context::default().until_err(&ctx).await, Err(Cause::Guard));
+}
+
+#[tokio::test]
+async fn completed_weak_if_completed() {
+    let ctx = Context::default();
+    let weak = ctx.completed_weak();
+    let guard = ctx.guard();
+    tokio::spawn(async move { drop(guard) });
+
+    assert_eq!(weak.await, WeakOutcome::Completed);
+    assert_eq!(ctx.completed_weak().await, WeakOutcome::Completed);
+}
+
+#[tokio::test]
+async fn completed_weak_if_dropped() {
+    let ctx = Context::default();
+    let weak = tokio::spawn(ctx.completed_weak());
+    tokio::task::yield_now().await;
+    drop(ctx);
+
+    assert_eq!(weak.await.unwrap(), WeakOutcome::Dropped);
+}
+
+#[tokio::test]
+async fn completed_weak_does_not_complete_on_drop() {
+    let ctx = Context::default();
+    let handle = ctx.handle();
+    let weak = ctx.completed_weak();
+    drop(ctx);
+
+    assert!(!handle.is_completed());
+    assert!(timeout(JIFFY, weak).await.is_err());
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn completion_increments_counter_once() {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+
+    metrics::with_local_recorder(&recorder, || {
+        let ctx = Context::default();
+        ctx.complete();
+        ctx.complete();
+        drop(ctx.guard());
+    });
+
+    let counters: Vec<_> = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .map(|(key, _, _, value)| (key.key().clone(), value))
+        .collect();
+
+    assert_eq!(counters.len(), 1);
+    let (key, value) = &counters[0];
+    assert_eq!(key.name(), "context_completions_total");
+    assert_eq!(
+        key.labels()
+            .map(|l| (l.key(), l.value()))
+            .collect::<Vec<_>>(),
+        vec![("cause", "completed")],
+    );
+    assert_eq!(value, &DebugValue::Counter(1));
+}
+
+#[tokio::test]
+async fn completed_borrows_context() {
+    let ctx = Context::default();
+    let guard = ctx.guard();
+    tokio::spawn(async move { drop(guard) });
+
+    ctx.completed().await;
+    ctx.completed().await;
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[tokio::test]
+async fn completed_map_yields_value() {
+    let ctx = Context::default();
+    let fut = ctx.completed_map("done");
+    ctx.complete();
+
+    assert_eq!(fut.await, "done");
+}
+
+#[test]
+fn dropping_last_clone_wakes_weak_waiters() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let waker = Arc::new(CountingWaker::default());
+    let waker_ref = Waker::from(waker.clone());
+    let mut cx = task::Context::from_waker(&waker_ref);
+
+    let mut weak = Box::pin(chd.completed_weak());
+    assert!(weak.as_mut().poll(&mut cx).is_pending());
+    drop(chd.clone());
+    assert_eq!(waker.count(), 0);
+    drop(chd);
+
+    assert_eq!(waker.count(), 1);
+    assert_eq!(
+        weak.as_mut().poll(&mut cx),
+        task::Poll::Ready(WeakOutcome::Dropped)
+    );
+    assert_eq!(ctx.cause(), None);
+}
+
+#[test]
+fn hierarchy_string_walks_ancestors() {
+    let root = Context::with_id(1);
+    let mid = root.child();
+    let at = Instant::now() + Duration::from_secs(60);
+    let chd = mid.child_with_deadline(at);
+    mid.complete();
+
+    let (mid_id, chd_id) = (mid.id(), chd.id());
+    assert_eq!(root.hierarchy_string(), "#1(root, pending)");
+    assert_eq!(
+        chd.hierarchy_string(),
+        format!(
+            "#{}(completed, deadline={:?}) <- #{}(completed) <- #1(root, pending)",
+            chd_id, at, mid_id,
+        ),
+    );
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn select_hands_back_unfinished_future() {
+    use futures::future::{select, Either};
+
+    let ctx = Context::default();
+    let other = Box::pin(tokio::time::sleep(JIFFY));
+    ctx.complete();
+
+    let other = match select(ctx.completed(), other).await {
+        Either::Left(((), other)) => other,
+        Either::Right(_) => panic!("context should have won"),
+    };
+    other.await;
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn select_against_recovers_unfinished_future() {
+    use futures::future::Either;
+
+    let ctx = Context::default();
+    ctx.complete();
+    let fut = match ctx.select_against(Box::pin(async { 42 })).await {
+        Either::Right(fut) => fut,
+        Either::Left(_) => panic!("context should have won"),
+    };
+
+    assert_eq!(fut.await, 42);
+}
+
+#[cfg(feature = "futures")]
+#[tokio::test]
+async fn select_against_if_future_wins() {
+    use futures::future::Either;
+
+    let ctx = Context::default();
+    let out = ctx.select_against(Box::pin(async { 42 })).await;
+
+    assert!(matches!(out, Either::Left(42)));
+}
+
+#[test]
+fn guards_complete_on_last_drop() {
+    let ctx = Context::default();
+    let mut guards = ctx.guards(3);
+
+    while let Some(guard) = guards.pop() {
+        assert_eq!(ctx.cause(), None);
+        drop(guard);
+    }
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[test]
+fn guards_share_count_with_shared_guard() {
+    let ctx = Context::default();
+    let guard = ctx.shared_guard();
+    drop(ctx.guards(2));
+
+    assert_eq!(ctx.cause(), None);
+    drop(guard);
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[tokio::test]
+async fn guards_are_drained() {
+    let ctx = Context::default();
+    let guards = ctx.guards(4);
+    let drain = ctx.drain_guards();
+
+    for guard in guards {
+        tokio::spawn(async move { drop(guard) });
+    }
+    timeout(JIFFY, drain).await.unwrap();
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn now_or_pending_is_ready_iff_completed() {
+    use futures::FutureExt;
+
+    let ctx = Context::default();
+    assert_eq!(ctx.now_or_pending().now_or_never(), None);
+
+    ctx.complete();
+    assert_eq!(ctx.now_or_pending().now_or_never(), Some(()));
+    assert_eq!(ctx.child().now_or_pending().now_or_never(), Some(()));
+}
+
+#[test]
+fn ancestors_walks_to_root() {
+    let root = Context::default();
+    let mid = root.child();
+    let chd = mid.child();
+
+    let ids: Vec<_> = chd.ancestors().map(|ctx| ctx.id()).collect();
+    assert_eq!(ids, vec![mid.id(), root.id()]);
+    assert_eq!(root.ancestors().count(), 0);
+    assert_eq!(chd.orphan().ancestors().count(), 0);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn completed_within_yields_cause() {
+    let ctx = Context::default();
+    let guard = ctx.guard();
+    tokio::spawn(async move {
+        tokio::time::sleep(JIFFY).await;
+        drop(guard);
+    });
+
+    assert_eq!(ctx.completed_within(JIFFY * 2).await, Cause::Guard);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn completed_within_yields_deadline_if_elapsed() {
+    let ctx = Context::default();
+
+    assert_eq!(ctx.completed_within(JIFFY).await, Cause::Deadline);
+    assert_eq!(ctx.cause(), None);
+}
+
+#[cfg(feature = "debug")]
+#[test]
+fn completed_at_reflects_call_site() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    assert_eq!(ctx.completed_at(), None);
+
+    let line = line!() + 1;
+    ctx.complete();
+    ctx.complete();
+
+    for ctx in [&ctx, &chd] {
+        let at = ctx.completed_at().unwrap();
+        assert_eq!((at.file(), at.line()), (file!(), line));
+    }
+}
+
+#[cfg(feature = "debug")]
+#[test]
+fn completed_at_through_try_complete() {
+    let ctx = Context::default();
+    let line = line!() + 1;
+    assert!(ctx.try_complete());
+
+    assert_eq!(ctx.completed_at().unwrap().line(), line);
+}
+
+#[cfg(feature = "pool")]
+#[test]
+fn pool_reuses_released_allocations() {
+    let pool = async_ctx::ContextPool::new(4);
+
+    for _ in 0..10 {
+        let ctx = pool.acquire();
+        ctx.complete();
+    }
+    assert_eq!(pool.allocations(), 1);
+
+    let held: Vec<_> = (0..6).map(|_| pool.acquire()).collect();
+    assert_eq!(pool.allocations(), 4);
+    drop(held);
+}
+
+#[cfg(feature = "pool")]
+#[tokio::test]
+async fn pool_resets_recycled_contexts() {
+    let pool = async_ctx::ContextPool::new(1);
+
+    let ctx = pool.acquire();
+    let id = ctx.id();
+    ctx.complete();
+    drop(ctx);
+
+    let ctx = pool.acquire();
+    assert_ne!(ctx.id(), id);
+    assert_eq!(ctx.cause(), None);
+    timeout(JIFFY, ctx.clone()).await.unwrap_err();
+    ctx.complete();
+    timeout(JIFFY, ctx).await.unwrap();
+    assert_eq!(pool.allocations(), 1);
+}
+
+#[cfg(feature = "pool")]
+#[tokio::test]
+async fn pool_does_not_reuse_observed_contexts() {
+    let pool = async_ctx::ContextPool::new(2);
+
+    let ctx = pool.acquire();
+    let weak = ctx.completed_weak();
+    drop(ctx);
+    let ctx = pool.acquire();
+
+    assert_eq!(pool.allocations(), 2);
+    ctx.complete();
+    timeout(JIFFY, weak).await.unwrap_err();
+}
+
+#[tokio::test]
+async fn completed_pinned_can_be_stored_and_polled() {
+    struct Worker {
+        done: Pin<Box<dyn Future<Output = ()> + Send>>,
+        polls: usize,
+    }
+
+    impl Future for Worker {
+        type Output = usize;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<usize> {
+            self.polls += 1;
+            let polls = self.polls;
+            self.done.as_mut().poll(cx).map(|()| polls)
+        }
+    }
+
+    let ctx = Context::default();
+    let worker = tokio::spawn(Worker {
+        done: ctx.completed_pinned(),
+        polls: 0,
+    });
+    tokio::task::yield_now().await;
+    ctx.complete();
+
+    assert!(worker.await.unwrap() >= 2);
+}
+
+#[tokio::test]
+async fn guard_completing_redirects_to_target() {
+    let src = Context::default();
+    let dst = Context::default();
+    let drain = dst.drain_guards();
+    let guard = src.guard_completing(&dst);
+    tokio::spawn(async move { drop(guard) });
+
+    timeout(JIFFY, dst.clone()).await.unwrap();
+    timeout(JIFFY, drain).await.unwrap();
+    assert_eq!(dst.cause(), Some(Cause::Guard));
+    assert_eq!(src.cause(), None);
+}
+
+#[cfg(feature = "stream")]
+#[tokio::test]
+async fn completed_stream_multi_yields_in_completion_order() {
+    use futures::StreamExt;
+
+    let ctxs: Vec<_> = (0..3).map(|_| Context::default()).collect();
+    let mut stream = Context::completed_stream_multi(ctxs.clone());
+    let guard = ctxs[0].guard();
+
+    ctxs[2].complete();
+    assert_eq!(stream.next().await, Some((2, Cause::Completed)));
+    drop(guard);
+    assert_eq!(stream.next().await, Some((0, Cause::Guard)));
+    ctxs[1].complete();
+    assert_eq!(stream.next().await, Some((1, Cause::Completed)));
+    assert_eq!(stream.next().await, None);
+}
+
+#[cfg(feature = "stream")]
+#[tokio::test]
+async fn stream_until_ends_on_completion() {
+    use async_ctx::prelude::*;
+    use futures::{stream, Stream, StreamExt};
+
+    let ctx = Context::default();
+    let mut items = stream::iter(0..3)
+        .chain(stream::pending())
+        .until_completed(&ctx);
+    assert_eq!(items.next().await, Some(0));
+    assert_eq!(items.next().await, Some(1));
+
+    ctx.complete();
+    assert_eq!(items.size_hint(), (0, Some(0)));
+    assert_eq!(items.next().await, None);
+
+    let rest: Vec<_> = ctx.stream_until(stream::iter(0..3)).collect().await;
+    assert!(rest.is_empty());
+    let all: Vec<_> = Context::default()
+        .stream_until(stream::iter(0..3))
+        .collect()
+        .await;
+    assert_eq!(all, [0, 1, 2]);
+}
+
+#[cfg(feature = "stream")]
+#[tokio::test]
+async fn completed_stream_multi_of_nothing_ends() {
+    use futures::StreamExt;
+
+    assert_eq!(Context::completed_stream_multi(vec![]).next().await, None);
+}
+
+#[test]
+fn is_completed_local_ignores_parents() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let other = ctx.child();
+    other.complete();
+    ctx.complete();
+
+    assert!(chd.is_completed());
+    assert!(!chd.is_completed_local());
+    assert!(other.is_completed());
+    assert!(other.is_completed_local());
+    assert!(ctx.is_completed_local());
+}
+
+#[test]
+fn is_completed_local_if_pending() {
+    let ctx = Context::default();
+
+    assert!(!ctx.is_completed());
+    assert!(!ctx.is_completed_local());
+}
+
+#[tokio::test]
+async fn completer_handle_completes_from_thread() {
+    let ctx = Context::default();
+    let completer = ctx.completer();
+    let producer = thread::spawn(move || {
+        thread::sleep(JIFFY);
+        completer.complete();
+    });
+
+    timeout(JIFFY * 10, ctx.child()).await.unwrap();
+    assert_eq!(ctx.cause(), Some(Cause::Completed));
+    producer.join().unwrap();
+}
+
+#[tokio::test]
+async fn any_completes_with_first_source() {
+    let a = Context::default();
+    let b = Context::default();
+    let any = Context::any(vec![a.clone(), b.clone()]);
+    timeout(JIFFY, any.clone()).await.unwrap_err();
+
+    drop(b.guard());
+    a.complete();
+    timeout(JIFFY, any.clone()).await.unwrap();
+    assert_eq!(any.cause(), Some(Cause::Guard));
+    assert_eq!(any.source(), Some(Source::Local));
+    assert_eq!(a.cause(), Some(Cause::Completed));
+}
+
+#[tokio::test]
+async fn all_completes_with_last_source() {
+    let a = Context::default();
+    let (b, chd) = (Context::default(), a.child());
+    let all = Context::all(vec![a.clone(), b.clone(), chd.clone()]);
+
+    a.complete();
+    timeout(JIFFY, all.clone()).await.unwrap_err();
+    drop(b.guard());
+    timeout(JIFFY, all.clone()).await.unwrap();
+    assert_eq!(all.cause(), Some(Cause::Guard));
+    assert_eq!(all.source(), Some(Source::Local));
+}
+
+#[test]
+fn all_of_nothing_is_completed() {
+    assert_eq!(Context::all(vec![]).cause(), Some(Cause::Completed));
+
+    let a = Context::default();
+    let all = Context::all(vec![a.clone()]);
+    all.complete();
+    assert_eq!(a.cause(), None);
+}
+
+#[cfg(feature = "pool")]
+#[test]
+fn dropped_fan_in_releases_sources() {
+    let pool = async_ctx::ContextPool::new(16);
+    let shutdown = Context::default();
+
+    for _ in 0..10 {
+        let conn = pool.acquire();
+        drop(Context::any(vec![shutdown.clone(), conn.clone()]));
+        drop(Context::all(vec![shutdown.clone(), conn.clone()]));
+        drop(shutdown.merge(&conn));
+    }
+    assert_eq!(pool.allocations(), 1);
+
+    let conn = pool.acquire();
+    let any = Context::any(vec![shutdown.clone(), conn.clone()]);
+    shutdown.complete();
+    assert_eq!(any.cause(), Some(Cause::Completed));
+}
+
+#[test]
+fn any_does_not_complete_sources() {
+    let a = Context::default();
+    let any = Context::any(vec![a.clone()]);
+    any.complete();
+
+    assert_eq!(a.cause(), None);
+    assert!(Context::any(vec![]).cause().is_none());
+}
+
+#[test]
+fn any_reports_first_cause_among_simultaneous() {
+    let a = Context::default();
+    let b = Context::default();
+    a.complete();
+    b.set_deadline(Instant::now());
+
+    let any = Context::any(vec![a, b]);
+    assert_eq!(any.cause(), Some(Cause::Completed));
+}
+
+#[test]
+fn any_by_reports_highest_ranked_cause() {
+    fn rank(cause: &Cause) -> u8 {
+        match cause {
+            Cause::Deadline => 2,
+            Cause::Guard => 1,
+            _ => 0,
+        }
+    }
+
+    let a = Context::default();
+    let b = Context::default();
+    a.complete();
+    b.set_deadline(Instant::now());
+
+    let any = Context::any_by(vec![a, b], |x, y| rank(x).cmp(&rank(y)));
+    assert_eq!(any.cause(), Some(Cause::Deadline));
+}
+
+#[test]
+fn any_by_breaks_ties_by_first_completion() {
+    let a = Context::default();
+    let b = Context::default();
+    let any = Context::any_by(vec![a.clone(), b.clone()], |_, _| std::cmp::Ordering::Equal);
+    drop(b.guard());
+    a.complete();
+
+    assert_eq!(any.cause(), Some(Cause::Guard));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn completed_or_deadline_yields_cause() {
+    let ctx = Context::default();
+    let at = tokio::time::Instant::now() + JIFFY * 2;
+    let chd = ctx.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(JIFFY).await;
+        chd.complete();
+    });
+
+    assert_eq!(
+        ctx.completed_or_deadline(at.into_std()).await,
+        Cause::Completed
+    );
+    assert!(tokio::time::Instant::now() < at);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn completed_or_deadline_yields_deadline_at_instant() {
+    let ctx = Context::default();
+    let at = tokio::time::Instant::now() + JIFFY;
+
+    assert_eq!(
+        ctx.completed_or_deadline(at.into_std()).await,
+        Cause::Deadline
+    );
+    assert_eq!(tokio::time::Instant::now(), at);
+    assert_eq!(ctx.cause(), None);
+}
+
+#[tokio::test]
+async fn guard_does_not_retain_parents() {
+    let ctx = Context::default();
+    let weak = ctx.completed_weak();
+    let chd = ctx.child();
+    let guard = chd.guard();
+    let handle = chd.handle();
+    drop((ctx, chd));
+
+    assert_eq!(timeout(JIFFY, weak).await.unwrap(), WeakOutcome::Dropped);
+    drop(guard);
+    assert!(handle.is_completed());
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn poll_parent_only_ignores_local_state() {
+    let root = Context::default();
+    let mid = root.child();
+    let chd = mid.child();
+
+    chd.complete();
+    assert!(chd.is_completed());
+    assert!(!chd.poll_parent_only());
+
+    root.complete();
+    assert!(chd.poll_parent_only());
+    assert!(mid.poll_parent_only());
+    assert!(!root.poll_parent_only());
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn poll_parent_only_ignores_severed_parents() {
+    let ctx = Context::default();
+    let chd = ctx.child().orphan();
+    ctx.complete();
+
+    assert!(!chd.poll_parent_only());
+}
+
+#[tokio::test]
+async fn merge_completes_with_either_side() {
+    let (a, b) = (Context::default(), Context::default());
+    let merged = a.merge(&b);
+    a.complete();
+    timeout(JIFFY, merged.clone()).await.unwrap();
+    assert_eq!(merged.cause(), Some(Cause::Completed));
+    assert_eq!(b.cause(), None);
+
+    let (a, b) = (Context::default(), Context::default());
+    let merged = a.merge(&b);
+    drop(b.guard());
+    timeout(JIFFY, merged.clone()).await.unwrap();
+    assert_eq!(merged.cause(), Some(Cause::Guard));
+    assert_eq!(a.cause(), None);
+}
+
+#[derive(Default)]
+struct Dependent(AtomicUsize);
+
+impl async_ctx::Notify for Dependent {
+    fn notify(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn dependents_are_notified_on_completion() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let dependent = Arc::new(Dependent::default());
+    chd.register_dependent(Arc::downgrade(&dependent) as _);
+    assert_eq!(dependent.0.load(Ordering::SeqCst), 0);
+
+    ctx.complete();
+    ctx.complete();
+    assert_eq!(dependent.0.load(Ordering::SeqCst), 1);
+
+    chd.register_dependent(Arc::downgrade(&dependent) as _);
+    assert_eq!(dependent.0.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn dependents_are_held_weakly() {
+    let ctx = Context::default();
+    let dependent = Arc::new(Dependent::default());
+    let weak = Arc::downgrade(&dependent);
+    ctx.register_dependent(weak.clone() as _);
+    drop(dependent);
+
+    assert_eq!(weak.strong_count(), 0);
+    ctx.complete();
+}
+
+#[test]
+fn recreated_completions_do_not_accumulate_wakers() {
+    let ctx = Context::default().child();
+    let waker = Arc::new(CountingWaker::default());
+    let waker_ref = Waker::from(waker.clone());
+    let mut cx = task::Context::from_waker(&waker_ref);
+
+    for _ in 0..10_000 {
+        let mut completion = ctx.completed();
+        assert!(Pin::new(&mut completion).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut completion).poll(&mut cx).is_pending());
+    }
+    assert_eq!(Arc::strong_count(&waker), 2);
+
+    let mut completion = ctx.completed();
+    assert!(Pin::new(&mut completion).poll(&mut cx).is_pending());
+    ctx.complete();
+    assert_eq!(waker.count(), 1);
+    assert!(Pin::new(&mut completion).poll(&mut cx).is_ready());
+}
+
+#[tokio::test]
+async fn completion_survives_select_loop() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for i in 0..100 {
+            tx.send(i).unwrap();
+            tokio::task::yield_now().await;
+        }
+        ctx.complete();
+    });
+
+    let mut received = 0;
+    loop {
+        tokio::select! {
+            biased;
+            _ = chd.completed() => break,
+            Some(_) = rx.recv() => received += 1,
+        }
+    }
+    assert_eq!(received, 100);
+}
+
+#[test]
+fn guard_with_can_complete() {
+    let ctx = Context::default();
+    drop(ctx.guard_with(|ctx| ctx.complete()));
+
+    assert_eq!(ctx.cause(), Some(Cause::Completed));
+}
+
+#[test]
+fn guard_with_can_skip_completion() {
+    let ctx = Context::default();
+    let (count, f) = counter();
+    drop(ctx.guard_with(move |_| f()));
+
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    assert_eq!(ctx.cause(), None);
+}
+
+#[test]
+fn guard_with_can_complete_another_context() {
+    let (src, dst) = (Context::default(), Context::default());
+    let target = dst.clone();
+    drop(src.guard_with(move |_| target.complete()));
+
+    assert_eq!(src.cause(), None);
+    assert_eq!(dst.cause(), Some(Cause::Completed));
+}
+
+#[tokio::test]
+async fn guard_with_releases_guard_on_panic() {
+    let ctx = Context::default();
+    let guard = ctx.guard_with(|_| panic!("on_drop panicked"));
+
+    panic::catch_unwind(panic::AssertUnwindSafe(|| drop(guard))).unwrap_err();
+    timeout(JIFFY, ctx.drain_guards()).await.unwrap();
+    assert_eq!(ctx.cause(), None);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn run_joinset_collects_all_results() {
+    let ctx = Context::default();
+    let mut set = tokio::task::JoinSet::new();
+    for i in 0..3 {
+        set.spawn(async move { i });
+    }
+
+    let results = ctx.run_joinset(&mut set).await;
+    let mut results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+    results.sort_unstable();
+    assert_eq!(results, vec![0, 1, 2]);
+    assert!(set.is_empty());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(start_paused = true)]
+async fn run_joinset_aborts_remaining_on_completion() {
+    let ctx = Context::default();
+    let mut set = tokio::task::JoinSet::new();
+    set.spawn(async { 1 });
+    for _ in 0..2 {
+        set.spawn(std::future::pending::<i32>());
+    }
+    let chd = ctx.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(JIFFY).await;
+        chd.complete();
+    });
+
+    let results = ctx.run_joinset(&mut set).await;
+    assert_eq!(results.len(), 1);
+    assert_eq!(*results[0].as_ref().unwrap(), 1);
+
+    while let Some(result) = set.join_next().await {
+        assert!(result.unwrap_err().is_cancelled());
+    }
+}
+
+#[test]
+fn reset_starts_a_new_generation() {
+    let ctx = Context::default();
+    assert!(!ctx.reset());
+
+    ctx.complete();
+    assert!(ctx.reset());
+    assert_eq!(ctx.cause(), None);
+    assert!(!ctx.is_completed());
+
+    drop(ctx.guard());
+    assert_eq!(ctx.cause(), Some(Cause::Guard));
+}
+
+#[test]
+fn completions_resolve_per_generation() {
+    let ctx = Context::default();
+    let waker = Arc::new(CountingWaker::default());
+    let waker_ref = Waker::from(waker.clone());
+    let mut cx = task::Context::from_waker(&waker_ref);
+
+    let mut stale = ctx.completed();
+    assert!(Pin::new(&mut stale).poll(&mut cx).is_pending());
+    ctx.complete();
+    assert!(ctx.reset());
+
+    let mut fresh = ctx.completed();
+    assert!(Pin::new(&mut fresh).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut stale).poll(&mut cx).is_ready());
+
+    ctx.complete();
+    assert!(Pin::new(&mut fresh).poll(&mut cx).is_ready());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn reset_leaves_children_completed() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    drop(ctx.guard());
+    assert!(ctx.reset());
+
+    assert_eq!(ctx.completed_within(JIFFY).await, Cause::Deadline);
+    assert_eq!(chd.cause(), Some(Cause::Guard));
+}
+
+#[test]
+fn reset_child_observes_completed_parent() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    ctx.complete();
+    assert!(chd.reset());
+
+    assert_eq!(chd.cause(), Some(Cause::Completed));
+    assert_eq!(chd.source(), Some(Source::Parent));
+}
+
+#[test]
+fn reset_child_observes_next_parent_completion() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    ctx.complete();
+    assert!(ctx.reset());
+    assert!(chd.reset());
+    assert!(!chd.is_completed());
+
+    drop(ctx.guard());
+    assert_eq!(chd.cause(), Some(Cause::Guard));
+    assert_eq!(chd.source(), Some(Source::Parent));
+}
+
+#[test]
+fn reset_orphan_doesnt_observe_former_parent() {
+    let ctx = Context::default();
+    let chd = ctx.child();
+    ctx.complete();
+    let chd = chd.orphan();
+    assert!(chd.reset());
+
+    assert!(!chd.is_completed());
+}
+
+#[test]
+fn woken_descendant_waiters_observe_cause() {
+    let ctx = Context::default();
+    let chds: Vec<_> = (0..16).map(|_| ctx.child().child()).collect();
+    let barrier = Arc::new(Barrier::new(chds.len() + 1));
+
+    let waiters: Vec<_> = chds
+        .iter()
+        .cloned()
+        .map(|chd| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap();
+                barrier.wait();
+                rt.block_on(chd.completed());
+                (chd.cause(), chd.handle().is_completed())
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    ctx.complete();
+    for waiter in waiters {
+        assert_eq!(waiter.join().unwrap(), (Some(Cause::Completed), true));
+    }
+}
+
+#[tokio::test]
+async fn group_completes_and_drains_children() {
+    let (root, group) = Context::new_group();
+    let exited = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..4 {
+        let chd = group.child();
+        let exited = exited.clone();
+        tokio::spawn(async move {
+            chd.completed().await;
+            tokio::task::yield_now().await;
+            exited.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    assert_eq!(group.live(), 4);
+    timeout(JIFFY, group.drained()).await.unwrap_err();
+
+    group.complete_all();
+    timeout(JIFFY, group.drained()).await.unwrap();
+    assert_eq!(group.live(), 0);
+    assert_eq!(exited.load(Ordering::SeqCst), 4);
+    assert_eq!(root.cause(), Some(Cause::Completed));
+}
+
+#[tokio::test]
+async fn group_drained_if_empty() {
+    let (_root, group) = Context::new_group();
+    drop(group.child());
+
+    timeout(JIFFY, group.drained()).await.unwrap();
+}
+
+#[tokio::test]
+async fn run_until_is_nameable() {
+    struct Task<'a> {
+        run: async_ctx::RunUntil<'a, Pin<Box<dyn Future<Output = u32> + Send>>>,
+        weak: async_ctx::WeakCompletion,
+    }
+
+    let ctx = Context::default();
+    let task = Task {
+        run: ctx.run_until(Box::pin(std::future::pending())),
+        weak: ctx.completed_weak(),
+    };
+    ctx.complete();
+
+    assert_eq!(task.run.await, None);
+    assert_eq!(task.weak.await, WeakOutcome::Completed);
+}
+
+#[test]
+fn repolled_completion_registers_once_per_waker() {
+    static CLONES: AtomicUsize = AtomicUsize::new(0);
+    static OTHER_CLONES: AtomicUsize = AtomicUsize::new(0);
+    let ctx = Context::default();
+    let waker = cloning_waker(&CLONES);
+    let other = cloning_waker(&OTHER_CLONES);
+    let mut completion = ctx.completed();
+
+    for _ in 0..1000 {
+        let mut cx = task::Context::from_waker(&waker);
+        assert!(Pin::new(&mut completion).poll(&mut cx).is_pending());
+    }
+    assert_eq!(CLONES.load(Ordering::SeqCst), 1);
+
+    let mut cx = task::Context::from_waker(&other);
+    assert!(Pin::new(&mut completion).poll(&mut cx).is_pending());
+    assert_eq!(OTHER_CLONES.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn repolled_observers_register_once() {
+    static WEAK: AtomicUsize = AtomicUsize::new(0);
+    static DRAIN: AtomicUsize = AtomicUsize::new(0);
+    static GROUP: AtomicUsize = AtomicUsize::new(0);
+    let (ctx, group) = Context::new_group();
+    let _chd = group.child();
+    let _guard = ctx.guard();
+    let mut weak = ctx.completed_weak();
+    let mut drain = Box::pin(ctx.drain_guards());
+    let mut drained = Box::pin(group.drained());
+
+    for _ in 0..1000 {
+        let waker = cloning_waker(&WEAK);
+        assert!(Pin::new(&mut weak)
+            .poll(&mut task::Context::from_waker(&waker))
+            .is_pending());
+        let waker = cloning_waker(&DRAIN);
+        assert!(drain
+            .as_mut()
+            .poll(&mut task::Context::from_waker(&waker))
+            .is_pending());
+        let waker = cloning_waker(&GROUP);
+        assert!(drained
+            .as_mut()
+            .poll(&mut task::Context::from_waker(&waker))
+            .is_pending());
+    }
+    assert_eq!(WEAK.load(Ordering::SeqCst), 1);
+    assert_eq!(DRAIN.load(Ordering::SeqCst), 1);
+    assert_eq!(GROUP.load(Ordering::SeqCst), 1);
+
+    drop(weak);
+    assert_eq!(ctx.complete_reporting(), Some(0));
+}
+
+#[test]
+fn dropped_completion_doesnt_unregister_context_future() {
+    let ctx = Context::default();
+    let waker = Arc::new(CountingWaker::default());
+    let counted = Waker::from(waker.clone());
+    let mut cx = task::Context::from_waker(&counted);
+
+    let mut completion = ctx.completed();
+    let mut fut = ctx.clone();
+    assert!(Pin::new(&mut completion).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut fut).poll(&mut cx).is_pending());
+    drop(completion);
+
+    ctx.complete();
+    assert_eq!(waker.count(), 1);
+}
+
+#[tokio::test]
+async fn biased_select_against_hot_branch_completes() {
+    let ctx = Context::default();
+    let completion = ctx.completed();
+    tokio::pin!(completion);
+
+    let mut iterations = 0;
+    loop {
+        tokio::select! {
+            biased;
+            _ = &mut completion => break,
+            _ = tokio::task::yield_now() => {
+                iterations += 1;
+                if iterations == 1000 {
+                    ctx.complete();
+                }
+            }
+        }
+    }
+    assert_eq!(iterations, 1000);
+}
+
+#[cfg(all(feature = "test-util", feature = "tokio"))]
+#[tokio::test]
+async fn assert_helpers_pass() {
+    let ctx = Context::default();
+    ctx.assert_pending().await;
+
+    ctx.complete();
+    ctx.assert_completed().await;
+    ctx.child().assert_completed().await;
+}
+
+#[cfg(all(feature = "test-util", feature = "tokio"))]
+#[tokio::test]
+async fn assert_helpers_panic() {
+    let ctx = Context::default();
+    let pending = ctx.clone();
+    let err = tokio::spawn(async move { pending.assert_completed().await })
+        .await
+        .unwrap_err();
+    assert!(err.is_panic());
+
+    ctx.complete();
+    let err = tokio::spawn(async move { ctx.assert_pending().await })
+        .await
+        .unwrap_err();
+    assert!(err.is_panic());
+}
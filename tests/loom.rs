@@ -0,0 +1,140 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Model checked tests, run with `RUSTFLAGS="--cfg loom" cargo test --test loom --release`.
+#![cfg(loom)]
+use async_ctx::{Cause, Context};
+use loom::{
+    future::block_on,
+    sync::atomic::{AtomicBool, Ordering::SeqCst},
+    thread,
+};
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{self, Wake, Waker},
+};
+
+#[derive(Default)]
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, SeqCst);
+    }
+}
+
+#[test]
+fn complete_wakes_concurrent_poll() {
+    loom::model(|| {
+        let ctx = Context::default();
+        let other = ctx.clone();
+        let completer = thread::spawn(move || other.complete());
+
+        block_on(ctx.clone());
+        completer.join().unwrap();
+        assert_eq!(ctx.cause(), Some(Cause::Completed));
+    });
+}
+
+#[test]
+fn parent_completion_wakes_concurrent_child_poll() {
+    loom::model(|| {
+        let ctx = Context::default();
+        let chd = ctx.child();
+        let completer = thread::spawn(move || ctx.complete());
+
+        block_on(chd.clone());
+        completer.join().unwrap();
+        assert_eq!(chd.cause(), Some(Cause::Completed));
+    });
+}
+
+#[test]
+fn concurrent_completions_have_one_winner() {
+    loom::model(|| {
+        let ctx = Context::default();
+        let other = ctx.clone();
+        let completer = thread::spawn(move || other.try_complete());
+
+        let won = ctx.try_complete();
+        let won_remotely = completer.join().unwrap();
+
+        assert!(won ^ won_remotely);
+        assert_eq!(ctx.cause(), Some(Cause::Completed));
+    });
+}
+
+#[test]
+fn child_derived_during_completion_is_completed() {
+    loom::model(|| {
+        let ctx = Context::default();
+        let other = ctx.clone();
+        let completer = thread::spawn(move || other.complete());
+
+        let chd = ctx.child();
+        block_on(chd.clone());
+        completer.join().unwrap();
+        assert!(chd.cause().is_some());
+    });
+}
+
+#[test]
+fn guard_drop_wakes_concurrent_drain() {
+    loom::model(|| {
+        let ctx = Context::default();
+        let guard = ctx.guard();
+        let drain = ctx.drain_guards();
+        let dropper = thread::spawn(move || drop(guard));
+
+        block_on(drain);
+        dropper.join().unwrap();
+        assert_eq!(ctx.cause(), Some(Cause::Guard));
+    });
+}
+
+#[test]
+fn woken_parent_waiter_observes_completed_children() {
+    loom::model(|| {
+        let ctx = Context::default();
+        let chd = ctx.child().child();
+        let other = ctx.clone();
+        let completer = thread::spawn(move || other.complete());
+
+        block_on(ctx.completed());
+        assert!(chd.handle().is_completed());
+        completer.join().unwrap();
+    });
+}
+
+#[test]
+fn pending_poll_is_woken_by_concurrent_complete() {
+    loom::model(|| {
+        let ctx = Context::default();
+        let other = ctx.clone();
+        let completer = thread::spawn(move || other.complete());
+
+        let flags = [Arc::new(Flag::default()), Arc::new(Flag::default())];
+        let wakers = flags.clone().map(Waker::from);
+        let mut owned = pin!(ctx.clone());
+        let mut borrowed = pin!(ctx.completed());
+        let polls = [
+            owned
+                .as_mut()
+                .poll(&mut task::Context::from_waker(&wakers[0])),
+            borrowed
+                .as_mut()
+                .poll(&mut task::Context::from_waker(&wakers[1])),
+        ];
+        completer.join().unwrap();
+
+        for (poll, flag) in polls.iter().zip(&flags) {
+            assert!(poll.is_ready() || flag.0.load(SeqCst));
+        }
+    });
+}